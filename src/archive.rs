@@ -0,0 +1,163 @@
+use gst;
+use gst::prelude::*;
+
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use failure::Error;
+
+use common::{get_request_pad, get_static_pad, make_element, utc_timestamp, NoSuchPad};
+use config::{Config, ConfigValueError};
+
+/// What the receiver archives, from `archive.format`.
+///
+/// `opus` writes the received Opus stream of the primary link into Ogg
+/// without decoding it again. `flac` encodes the programme as it goes to
+/// the output, so the archive also holds the backup or fallback audio
+/// whenever that was on air.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArchiveFormat {
+    Opus,
+    Flac,
+}
+
+impl ArchiveFormat {
+    fn extension(&self) -> &'static str {
+        match *self {
+            ArchiveFormat::Opus => "opus",
+            ArchiveFormat::Flac => "oga",
+        }
+    }
+}
+
+/// Records the programme into `<section>.dir`, in a new Ogg file named
+/// `<section>.prefix-<UTC time>` every `<section>.rotate` minutes (default
+/// 60) counted from midnight UTC, so with the default files start on the
+/// hour. The receiver archives under `archive`, the transmitter keeps its
+/// local copy under `record`.
+#[derive(Debug, Clone)]
+pub struct Archive {
+    pub format: ArchiveFormat,
+    dir: String,
+    prefix: String,
+    rotate: u64,
+}
+
+impl Archive {
+    /// The receiver archive, in the format of `archive.format`.
+    pub fn from_config(config: &Config, port: i32) -> Result<Option<Archive>, Error> {
+        let format = match config.get_str("archive.format") {
+            None | Some("opus") => ArchiveFormat::Opus,
+            Some("flac") => ArchiveFormat::Flac,
+            Some(other) => {
+                return Err(Error::from(ConfigValueError("archive.format".into(), other.into())))
+            }
+        };
+        Archive::from_section(config, "archive", format, format!("tlink-{}", port))
+    }
+
+    /// The transmitter's copy of the Opus stream it sends.
+    pub fn local_copy(config: &Config, port: i32) -> Result<Option<Archive>, Error> {
+        Archive::from_section(config, "record", ArchiveFormat::Opus, format!("tlink-tx-{}", port))
+    }
+
+    fn from_section(
+        config: &Config,
+        section: &str,
+        format: ArchiveFormat,
+        default_prefix: String,
+    ) -> Result<Option<Archive>, Error> {
+        let key = |name: &str| format!("{}.{}", section, name);
+        let dir = match config.get_str(&key("dir")) {
+            Some(dir) => dir.to_string(),
+            None => return Ok(None),
+        };
+        fs::create_dir_all(&dir)?;
+
+        let rotate = config.get_or(&key("rotate"), 60u64)?;
+        if rotate == 0 {
+            return Err(Error::from(ConfigValueError(key("rotate"), "0".into())));
+        }
+
+        Ok(Some(Archive {
+            format,
+            dir,
+            prefix: config
+                .get_str(&key("prefix"))
+                .map(String::from)
+                .unwrap_or(default_prefix),
+            rotate: rotate * 60,
+        }))
+    }
+
+    /// Makes a bin that takes the Opus stream or the raw programme,
+    /// depending on the format, and writes the archive files. A slow disk
+    /// loses archive data rather than holding up the live stream.
+    pub fn make_recorder(&self) -> Result<gst::Element, Error> {
+        let queue = make_element("queue", None)?;
+        queue.set_property_from_str("leaky", "downstream");
+        let mut chain = vec![queue];
+        match self.format {
+            ArchiveFormat::Opus => chain.push(make_element("opusparse", None)?),
+            ArchiveFormat::Flac => {
+                chain.push(make_element("audioconvert", None)?);
+                chain.push(make_element("flacenc", None)?);
+            }
+        }
+
+        let splitmuxsink = make_element("splitmuxsink", None)?;
+        splitmuxsink.set_property("muxer", &make_element("oggmux", None)?.to_value())?;
+        self.name_files(&splitmuxsink)?;
+        self.rotate_files(&splitmuxsink);
+
+        let bin = gst::Bin::new(None);
+        {
+            let elements = chain.iter().collect::<Vec<_>>();
+            bin.add_many(&elements)?;
+            gst::Element::link_many(&elements)?;
+        }
+        bin.add(&splitmuxsink)?;
+        let srcpad = get_static_pad(&chain[chain.len() - 1], "src")?;
+        let sinkpad = get_request_pad(&splitmuxsink, "audio_%u")?;
+        srcpad.link(&sinkpad).into_result()?;
+
+        let ghost = gst::GhostPad::new("sink", &get_static_pad(&chain[0], "sink")?)
+            .ok_or_else(|| Error::from(NoSuchPad("sink", bin.get_name())))?;
+        bin.add_pad(&ghost)?;
+
+        Ok(bin.upcast::<gst::Element>())
+    }
+
+    fn name_files(&self, splitmuxsink: &gst::Element) -> Result<(), Error> {
+        let dir = self.dir.clone();
+        let prefix = self.prefix.clone();
+        let extension = self.format.extension();
+        splitmuxsink.connect("format-location", false, move |_| {
+            let name = format!("{}-{}.{}", prefix, utc_timestamp(SystemTime::now()), extension);
+            let path = Path::new(&dir).join(name).to_string_lossy().into_owned();
+            eprintln!("Recording to {}", path);
+            Some(path.to_value())
+        })?;
+        Ok(())
+    }
+
+    /// Starts a new file at every multiple of the rotation period.
+    fn rotate_files(&self, splitmuxsink: &gst::Element) {
+        let period = self.rotate;
+        let splitmuxsink = splitmuxsink.clone();
+        thread::spawn(move || loop {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_else(|_| Duration::from_secs(0));
+            let into_period = now.as_secs() % period;
+            thread::sleep(Duration::from_secs(period - into_period) - Duration::new(0, now.subsec_nanos()));
+
+            if let Err(err) = splitmuxsink.emit("split-now", &[]) {
+                eprintln!("Cannot rotate recording files: {}", err);
+                return;
+            }
+        });
+    }
+}
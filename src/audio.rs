@@ -0,0 +1,133 @@
+use gst;
+use gst::prelude::*;
+
+use byte_slice_cast::*;
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use failure::Error;
+
+/// Interleaved samples of one buffer, converted to f32 in [-1, 1].
+#[derive(Debug, Clone)]
+pub struct AudioBlock {
+    pub rate: u32,
+    pub channels: usize,
+    pub samples: Vec<f32>,
+}
+
+impl AudioBlock {
+    pub fn frames(&self) -> usize {
+        self.samples.len() / self.channels
+    }
+
+    /// Samples of channel `channel` only.
+    pub fn channel(&self, channel: usize) -> Vec<f32> {
+        self.samples
+            .iter()
+            .skip(channel)
+            .step_by(self.channels)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Reads a raw audio buffer passing `pad`, using the pad's current caps.
+/// Only the native-endian F32 and S16 formats the pipelines negotiate
+/// between audioconvert, opus and jack are understood.
+pub fn read_block(pad: &gst::Pad, buffer: &gst::BufferRef) -> Option<AudioBlock> {
+    let caps = pad.get_current_caps()?;
+    let structure = caps.get_structure(0)?;
+    let format = structure.get::<&str>("format")?;
+    let rate = structure.get::<i32>("rate")? as u32;
+    let channels = structure.get::<i32>("channels")? as usize;
+
+    let map = buffer.map_readable()?;
+    let samples = match format {
+        "F32LE" => map.as_slice().as_slice_of::<f32>().ok()?.to_vec(),
+        "S16LE" => map
+            .as_slice()
+            .as_slice_of::<i16>()
+            .ok()?
+            .iter()
+            .map(|&s| s as f32 / 32768.0)
+            .collect(),
+        _ => return None,
+    };
+
+    Some(AudioBlock {
+        rate,
+        channels,
+        samples,
+    })
+}
+
+/// Level in dBFS of a mean square value, floored at -100 dBFS.
+pub fn power_to_db(mean_square: f64) -> f64 {
+    if mean_square <= 1e-10 {
+        -100.0
+    } else {
+        10.0 * mean_square.log10()
+    }
+}
+
+/// Writes `samples` back into a buffer read with `read_block`, in the
+/// format of the pad's current caps.
+pub fn write_block(pad: &gst::Pad, buffer: &mut gst::BufferRef, samples: &[f32]) -> Option<()> {
+    let caps = pad.get_current_caps()?;
+    let structure = caps.get_structure(0)?;
+    let format = structure.get::<&str>("format")?;
+
+    let mut map = buffer.map_writable()?;
+    match format {
+        "F32LE" => {
+            let data = map.as_mut_slice().as_mut_slice_of::<f32>().ok()?;
+            data.copy_from_slice(samples);
+        }
+        "S16LE" => {
+            let data = map.as_mut_slice().as_mut_slice_of::<i16>().ok()?;
+            for (out, &s) in data.iter_mut().zip(samples) {
+                *out = (s * 32768.0).max(-32768.0).min(32767.0) as i16;
+            }
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+/// Writes interleaved samples as a 16 bit PCM WAV file.
+pub fn write_wav(path: &str, rate: u32, channels: usize, samples: &[f32]) -> Result<(), Error> {
+    let mut file = BufWriter::new(File::create(path)?);
+    let data_len = (samples.len() * 2) as u32;
+    let block_align = (channels * 2) as u16;
+
+    let mut header = Vec::with_capacity(44);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&le32(36 + data_len));
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&le32(16));
+    header.extend_from_slice(&le16(1));
+    header.extend_from_slice(&le16(channels as u16));
+    header.extend_from_slice(&le32(rate));
+    header.extend_from_slice(&le32(rate * block_align as u32));
+    header.extend_from_slice(&le16(block_align));
+    header.extend_from_slice(&le16(16));
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&le32(data_len));
+    file.write_all(&header)?;
+
+    for &sample in samples {
+        let value = (sample * 32768.0).max(-32768.0).min(32767.0) as i16;
+        file.write_all(&le16(value as u16))?;
+    }
+    file.flush()?;
+    Ok(())
+}
+
+fn le16(value: u16) -> [u8; 2] {
+    [value as u8, (value >> 8) as u8]
+}
+
+fn le32(value: u32) -> [u8; 4] {
+    [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]
+}
@@ -1,16 +1,61 @@
-#[macro_use]
 extern crate gstreamer as gst;
 use gst::prelude::*;
+extern crate gstreamer_app as gst_app;
 extern crate glib;
 
 
 use std::env;
+use std::sync::{Arc, Mutex};
 use std::error::Error as StdError;
 use std::time;
 use std::thread;
 
-#[path = "../common.rs"]
-mod common;
+extern crate wappuradio_tlink;
+use wappuradio_tlink::common;
+use common::{get_static_pad, make_element};
+
+use wappuradio_tlink::config;
+use config::Config;
+
+use wappuradio_tlink::rtpdump;
+
+use wappuradio_tlink::level;
+use level::LevelStats;
+
+use wappuradio_tlink::link;
+use link::ReceiverLink;
+
+use wappuradio_tlink::silence;
+
+use wappuradio_tlink::selector;
+use selector::{Failover, LinkHealth, Selector};
+
+use wappuradio_tlink::fallback;
+
+use wappuradio_tlink::archive;
+use archive::{Archive, ArchiveFormat};
+
+use wappuradio_tlink::conceal;
+use conceal::Concealment;
+
+use wappuradio_tlink::control;
+
+use wappuradio_tlink::delay;
+use delay::DelayLine;
+
+use wappuradio_tlink::loudness;
+use loudness::Loudness;
+
+use wappuradio_tlink::audio;
+
+use wappuradio_tlink::tone;
+use tone::ToneAnalyzer;
+
+use wappuradio_tlink::latency;
+
+use wappuradio_tlink::prbs;
+
+use wappuradio_tlink::snapshot;
 
 extern crate failure;
 use failure::Error;
@@ -19,19 +64,7 @@ use failure::Error;
 extern crate failure_derive;
 
 #[derive(Debug, Fail)]
-#[fail(display = "Missing element {}", _0)]
-struct MissingElement(&'static str);
-
-#[derive(Debug, Fail)]
-#[fail(display = "No such pad {} in {}", _0, _1)]
-struct NoSuchPad(&'static str, String);
-
-#[derive(Debug, Fail)]
-#[fail(display = "Unknown payload type {}", _0)]
-struct UnknownPT(u32);
-
-#[derive(Debug, Fail)]
-#[fail(display = "Usage: {} PORT LATENCY SIZE-TIME(ms)", _0)]
+#[fail(display = "Usage: {} PORT LATENCY SIZE-TIME(ms) [CONFIG]", _0)]
 struct UsageError(String);
 
 #[derive(Debug, Fail)]
@@ -44,73 +77,146 @@ struct ErrorMessage {
     cause: glib::Error,
 }
 
-fn make_element<'a, P: Into<Option<&'a str>>>(
-    factory_name: &'static str,
-    element_name: P,
-) -> Result<gst::Element, Error> {
-    match gst::ElementFactory::make(factory_name, element_name.into()) {
-        Some(elem) => Ok(elem),
-        None => Err(Error::from(MissingElement(factory_name))),
-    }
+/// Analyses the first channel of the audio passing `pad` for the test tone
+/// and prints a report every `tone.report` seconds.
+fn attach_tone_analyzer(pad: &gst::Pad, config: &Config, freq: f64) -> Result<(), Error> {
+    let report_secs = config.get_or("tone.report", 5u64)?;
+    let tolerance = config.get_or("tone.tolerance", 1.0f64)?;
+    let analyzer = Arc::new(Mutex::new(ToneAnalyzer::new(freq)));
+
+    let probe_analyzer = analyzer.clone();
+    pad.add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+        if let Some(gst::PadProbeData::Buffer(ref buffer)) = info.data {
+            if let Some(block) = audio::read_block(pad, buffer) {
+                probe_analyzer.lock().unwrap().push(block.rate, &block.channel(0));
+            }
+        }
+        gst::PadProbeReturn::Ok
+    });
+
+    thread::spawn(move || loop {
+        thread::sleep(time::Duration::from_secs(report_secs));
+        let report = analyzer.lock().unwrap().take_report();
+        let verdict = if report.is_clean(tolerance) { "clean" } else { "NOT CLEAN" };
+        println!("Tone {} Hz: {}: {}", freq, verdict, report);
+    });
+
+    Ok(())
 }
 
-fn get_static_pad(element: &gst::Element, pad_name: &'static str) -> Result<gst::Pad, Error> {
-    match element.get_static_pad(pad_name) {
-        Some(pad) => Ok(pad),
-        None => {
-            let element_name = element.get_name();
-            Err(Error::from(NoSuchPad(pad_name, element_name)))
+/// Measures when the transmitter's latency pulses are played out and
+/// prints the statistics every `latency.report` seconds.
+fn attach_latency_detector(
+    pad: &gst::Pad,
+    pipeline: &gst::Pipeline,
+    config: &Config,
+    interval_ms: u64,
+) -> Result<(), Error> {
+    let report_secs = config.get_or("latency.report", 10u64)?;
+    let offset_ms = config.get_or("latency.offset", 0u64)?;
+    let detector = latency::attach_pulse_detector(pad, interval_ms * 1_000_000, offset_ms * 1_000_000);
+
+    let pipeline = pipeline.clone();
+    thread::spawn(move || {
+        for second in 1.. {
+            thread::sleep(time::Duration::from_secs(1));
+            let pipeline_latency = latency::pipeline_latency(&pipeline);
+            let mut detector = detector.lock().unwrap();
+            detector.set_pipeline_latency(pipeline_latency);
+            if second % report_secs == 0 {
+                println!("Latency: {}", detector.take_report());
+            }
         }
-    }
+    });
+
+    Ok(())
 }
 
-fn get_request_pad(element: &gst::Element, pad_name: &'static str) -> Result<gst::Pad, Error> {
-    match element.get_request_pad(pad_name) {
-        Some(pad) => Ok(pad),
-        None => {
-            let element_name = element.get_name();
-            Err(Error::from(NoSuchPad(pad_name, element_name)))
-        }
+/// Packets the receiver could not recover so far, with the stats they were
+/// taken from. Without FEC every packet the jitterbuffer lost counts.
+fn unrecovered_packets(pipeline: &gst::Pipeline) -> (u64, String) {
+    let fec = pipeline.get_by_name("fecdec").map(|fecdec| {
+        let recovered = fecdec.get_property("recovered").ok().and_then(|v| v.get::<u32>()).unwrap_or(0);
+        let unrecovered = fecdec.get_property("unrecovered").ok().and_then(|v| v.get::<u32>()).unwrap_or(0);
+        (recovered, unrecovered)
+    });
+    let jitterbuffer_stats = pipeline
+        .get_by_name("rtpjitterbuffer0")
+        .and_then(|jitterbuffer| jitterbuffer.get_property("stats").ok())
+        .and_then(|value| value.get::<gst::Structure>());
+    let lost = jitterbuffer_stats
+        .as_ref()
+        .and_then(|stats| stats.get::<u64>("num-lost"))
+        .unwrap_or(0);
+
+    let mut description = String::new();
+    if let Some((recovered, unrecovered)) = fec {
+        description.push_str(&format!("FEC recovered {}, unrecovered {}\n", recovered, unrecovered));
+    }
+    if let Some(ref stats) = jitterbuffer_stats {
+        description.push_str(&format!("Jitterbuffer {}\n", stats.to_string()));
+    }
+
+    match fec {
+        Some((_, unrecovered)) => (unrecovered as u64, description),
+        None => (lost, description),
     }
 }
 
-fn connect_rtpbin_srcpad(src_pad: &gst::Pad, sink: &gst::Element) -> Result<(), Error> {
-    let name = src_pad.get_name();
-    let split_name = name.split("_");
-    let split_name = split_name.collect::<Vec<&str>>();
-    let pt = split_name[5].parse::<u32>()?;
-    match pt {
-        96 => {
-            let sinkpad = get_static_pad(sink, "sink")?;
-            src_pad.link(&sinkpad).into_result()?;
-            Ok(())
-        }
-        _ => Err(Error::from(UnknownPT(pt))),
+/// Decodes the backup link of session 1 the same way as the primary, up to
+/// the format the output mixer takes.
+fn make_backup_decoder(config: &Config, concealment: Option<&Arc<Concealment>>) -> Result<gst::Element, Error> {
+    let depay = make_element("rtpopusdepay", "backupdepay")?;
+    let queue1 = make_element("queue", None)?;
+    let opusdec = make_element("opusdec", None)?;
+    let queue2 = make_element("queue", None)?;
+    let audioconvert = make_element("audioconvert", None)?;
+    let backuplevel = level::make_level(config, "backuplevel")?;
+    let chain = selector::make_input_chain()?;
+    opusdec.set_property("plc", &true.to_value())?;
+    if let Some(concealment) = concealment {
+        conceal::attach(concealment, &opusdec)?;
     }
+
+    common::make_bin(&[&depay, &queue1, &opusdec, &queue2, &audioconvert, &backuplevel, &chain])
+}
+
+/// Links `src` to a tee that also feeds the archive recorder and returns
+/// the tee to continue the pipeline from.
+fn tee_archive(pipeline: &gst::Pipeline, src: &gst::Element, archive: &Archive) -> Result<gst::Element, Error> {
+    let tee = make_element("tee", "archivetee")?;
+    let recorder = archive.make_recorder()?;
+    pipeline.add_many(&[&tee, &recorder])?;
+    src.link(&tee)?;
+    tee.link(&recorder)?;
+    Ok(tee)
 }
 
-fn make_fec_decoder(rtpbin: &gst::Element, sess_id: u32) -> Result<gst::Element, Error> {
-    let fecdec = make_element("rtpulpfecdec", "fecdec")?;
-    let internal_storage = rtpbin
-        .emit("get-internal-storage", &[&sess_id.to_value()])
-        .unwrap()
-        .unwrap();
-
-    fecdec.set_property("storage", &internal_storage.to_value())?;
-    fecdec.set_property("pt", &100u32.to_value())?;
-    println!("Making fecdec");
-    let recovered = fecdec.get_property("recovered");
-    let unrecovered = fecdec.get_property("unrecovered");
-    println!("{:?}",recovered);
-    println!("{:?}", unrecovered);
-    Ok(fecdec)
+/// Mixes `inputs`, already in the mixer format, into `output` with the
+/// first one on air.
+fn make_output_switch(
+    pipeline: &gst::Pipeline,
+    config: &Config,
+    inputs: &[(&str, gst::Element)],
+    output: &gst::Element,
+) -> Result<Selector, Error> {
+    let mixer = make_element("audiomixer", "mixer")?;
+    pipeline.add(&mixer)?;
+    mixer.link(output)?;
+
+    let crossfade = time::Duration::from_millis(config.get_or("fallback.crossfade", 500u64)?);
+    let mut selector = Selector::new(&mixer, crossfade);
+    for &(name, ref input) in inputs {
+        selector.add_input(name, input)?;
+    }
+    Ok(selector)
 }
 
 fn example_main() -> Result<(), Error> {
     gst::init()?;
 
     let args: Vec<_> = env::args().collect();
-    if args.len() != 4 {
+    if args.len() != 4 && args.len() != 5 {
         return Err(Error::from(UsageError(args[0].clone())));
     }
 
@@ -118,15 +224,31 @@ fn example_main() -> Result<(), Error> {
     let port = args[1].parse::<i32>()?;
     let latency = args[2].parse::<u32>()?;
     let size_time_ms = args[3].parse::<u64>()?;
+    let config = Config::from_args(&args, 4)?;
 
     let pipeline = gst::Pipeline::new(None);
-    let udpsrc = make_element("udpsrc", None)?;
-    let rtpbin = make_element("rtpbin", None)?;
-    let rtpopusdepay = make_element("rtpopusdepay", "depay")?;
+    let pcm_test = prbs::PcmTest::from_config(&config)?;
+    // A backup transmitter sends the same programme to `backup.port`, over
+    // the transport and NAT settings given as `backup.<key>` where they
+    // differ from the primary's, e.g. `backup.nat.peer`. The PCM test
+    // checks a single stream and ignores it.
+    let backup_port = match pcm_test {
+        Some(_) => None,
+        None => config.get::<i32>("backup.port")?,
+    };
+    let archive = match pcm_test {
+        Some(_) => None,
+        None => Archive::from_config(&config, port)?,
+    };
+    let depay = match pcm_test {
+        Some(ref test) => make_element(test.format.depayloader(), "depay")?,
+        None => make_element("rtpopusdepay", "depay")?,
+    };
     let queue1 = make_element("queue", None)?;
     let opusdec = make_element("opusdec", None)?;
     let queue2 = make_element("queue", None)?;
     let audioconvert = make_element("audioconvert", None)?;
+    let level = level::make_level(&config, "level")?;
     let jackaudiosink = make_element("jackaudiosink", None)?;
 
     /*
@@ -137,55 +259,131 @@ fn example_main() -> Result<(), Error> {
     let scale = make_element("videoscale", None)?;
     let filter = make_element("capsfilter", None)?;
 	*/
-    pipeline.add_many(&[&udpsrc, &rtpbin, &rtpopusdepay, &queue1, &opusdec, &queue2, &audioconvert, &jackaudiosink])?;
+    pipeline.add_many(&[&depay, &queue1])?;
     // TODO: Check what actually need to be linked
-    gst::Element::link_many(&[&rtpopusdepay, &queue1, &opusdec, &queue2, &audioconvert, &jackaudiosink])?;
-
-
-    rtpbin.connect("new-storage", false, move |values| {
-        let storage = values[1].get::<gst::Element>().expect("Invalid argument");
-        let size_time_ns  = &size_time_ms * 1000000u64;
-        storage
-            .set_property("size-time", &size_time_ns.to_value())
-            .unwrap();
-
-        None
-    })?;
-
-    rtpbin.connect("request-pt-map", false, |values| {
-        let pt = values[2].get::<u32>().expect("Invalid argument");
-        match pt {
-            100 => Some(
-                gst::Caps::new_simple(
-                    "application/x-rtp",
-                    &[
-                        ("media", &"audio"),
-                        ("clock-rate", &48000i32),
-                        ("is-fec", &true),
-                    ],
-                ).to_value(),
-            ),
-            96 => Some(
-                gst::Caps::new_simple(
-                    "application/x-rtp",
-                    &[
-                        ("media", &"audio"),
-                        ("clock-rate", &48000i32),
-                        ("encoding-name", &"OPUS"),
-                    ]
-                ).to_value(),
-            ),
-            _ => None,
+    let mut output_switch = None;
+    let mut backup_decoder = None;
+    let mut programme_delay = None;
+    // Concealment fading of the links in order of preference, primary first.
+    let mut concealments = Vec::new();
+    let pcm_checker = match pcm_test {
+        Some(ref test) => {
+            let (checker_sink, checker) = prbs::make_checker(test)?;
+            pipeline.add(&checker_sink)?;
+            gst::Element::link_many(&[&depay, &queue1, &checker_sink])?;
+            Some(checker)
         }
-    })?;
-
+        None => {
+            pipeline.add_many(&[&opusdec, &queue2, &audioconvert, &level, &jackaudiosink])?;
+            let received = match archive {
+                Some(ref archive) if archive.format == ArchiveFormat::Opus => {
+                    tee_archive(&pipeline, &depay, archive)?
+                }
+                _ => depay.clone(),
+            };
+            gst::Element::link_many(&[&received, &queue1, &opusdec, &queue2, &audioconvert])?;
+            if let Some(concealment) = Concealment::from_config(&config)? {
+                conceal::attach(&concealment, &opusdec)?;
+                concealments.push(concealment);
+            }
+            match archive {
+                Some(ref archive) if archive.format == ArchiveFormat::Flac => {
+                    let tee = tee_archive(&pipeline, &level, archive)?;
+                    let queue3 = make_element("queue", None)?;
+                    pipeline.add(&queue3)?;
+                    gst::Element::link_many(&[&tee, &queue3, &jackaudiosink])?;
+                }
+                _ => level.link(&jackaudiosink)?,
+            }
+            // The delay takes the decoded programme before the output and
+            // its meter. With a backup link or fallback it sits after the
+            // mixer rather than on the primary's decoder, so every source
+            // is delayed alike: a failover does not jump the programme by
+            // the delay, and a dump skips whatever is on air.
+            programme_delay = DelayLine::from_config(&config)?;
+            let programme = match programme_delay {
+                Some(ref line) => {
+                    let (delay_sink, delay_source) = delay::make_elements(line)?;
+                    pipeline.add_many(&[&delay_sink, &delay_source])?;
+                    delay_source.link(&level)?;
+                    delay_sink
+                }
+                None => level.clone(),
+            };
+            if backup_port.is_some() {
+                let concealment = Concealment::from_config(&config)?;
+                backup_decoder = Some(make_backup_decoder(&config, concealment.as_ref())?);
+                concealments.extend(concealment);
+            }
+            let fallback_source = fallback::make_source(&config)?;
+            if backup_decoder.is_some() || fallback_source.is_some() {
+                let linklevel = level::make_level(&config, "linklevel")?;
+                let link_chain = selector::make_input_chain()?;
+                pipeline.add_many(&[&linklevel, &link_chain])?;
+                gst::Element::link_many(&[&audioconvert, &linklevel, &link_chain])?;
+
+                let mut inputs = Vec::new();
+                match backup_decoder {
+                    Some(ref backup) => {
+                        pipeline.add(backup)?;
+                        inputs.push(("primary", link_chain));
+                        inputs.push(("backup", backup.clone()));
+                    }
+                    None => inputs.push(("link", link_chain)),
+                }
+                if let Some(source) = fallback_source {
+                    pipeline.add(&source)?;
+                    inputs.push(("fallback", source));
+                }
+                let switch = make_output_switch(&pipeline, &config, &inputs, &programme)?;
+                output_switch = Some(Arc::new(Mutex::new(switch)));
+            } else {
+                audioconvert.link(&programme)?;
+            }
+            None
+        }
+    };
+
+    // Depayloaders by RTP session, the primary link first.
+    let mut depays = vec![depay.clone()];
+    depays.extend(backup_decoder);
+    let ReceiverLink {
+        netsrcs,
+        injected_loss,
+        one_way_delay,
+        ..
+    } = link::make_receiver(&pipeline, &config, port, backup_port, latency, size_time_ms, depays)?;
+    let netsrc = &netsrcs[0];
+
+    // Health of the links in order of preference, primary first.
+    let mut link_health = Vec::new();
+    if output_switch.is_some() {
+        let health = LinkHealth::from_config(&config)?;
+        LinkHealth::attach(&health, &get_static_pad(netsrc, "src")?);
+        link_health.push(health);
+    }
+    if let Some(backup_netsrc) = netsrcs.get(1) {
+        let health = LinkHealth::from_config(&config.with_section("backup"))?;
+        LinkHealth::attach(&health, &get_static_pad(backup_netsrc, "src")?);
+        link_health.push(health);
+    }
    
     //udpsrc.link(&rtpopusdepay);
 
     //udpsrc.link(&rtpbin); 
-    let srcpad = get_static_pad(&udpsrc, "src")?;
-    let sinkpad = get_request_pad(&rtpbin, "recv_rtp_sink_0")?;
-    srcpad.link(&sinkpad).into_result()?;
+    let capture = match config.get_str("capture.file") {
+        Some(path) => Some(rtpdump::attach_capture(&get_static_pad(netsrc, "src")?, path, port)?),
+        None => None,
+    };
+    let incident_snapshot = snapshot::Snapshot::from_config(&config, port)?;
+    if let Some(ref snapshot) = incident_snapshot {
+        snapshot::attach_packets(snapshot, &get_static_pad(netsrc, "src")?);
+        // The PCM test neither decodes nor plays out any audio.
+        if pcm_test.is_none() {
+            snapshot::attach_audio(snapshot, &get_static_pad(&jackaudiosink, "sink")?);
+            snapshot::attach_concealment(snapshot, &opusdec)?;
+        }
+    }
     
    /* let srcpad2 = get_request_pad(&rtpbin, "send_rtp_src_0")?;
     let sinkpad2 = get_static_pad(&rtpopusdepay, "sink")?;
@@ -198,51 +396,20 @@ fn example_main() -> Result<(), Error> {
     let sinkpad = get_request_pad(&rtpbin, "recv_rtp_sink_0")?;
     srcpad.link(&sinkpad).into_result()?;
     */
-    // How this works depend on the implementation of the library.
-    let depay_clone = rtpopusdepay.clone();
     //rtpbin.link(&rtpopusdepay)?;
-    rtpbin.connect_pad_added(move |rtpbin, src_pad| {
-        rtpbin.unlink(&depay_clone);
-     
-        match connect_rtpbin_srcpad(&src_pad, &depay_clone) {
-            Ok(_) => (),
-            Err(err) => {
-                gst_element_error!(
-                    rtpbin,
-                    gst::LibraryError::Failed,
-                    ("Failed to link srcpad"),
-                    ["{}", err]
-                );
-                ()
-            }
-        }
-        rtpbin.connect("request-fec-decoder", false, |values| {
-        let rtpbin = values[0].get::<gst::Element>().expect("Invalid argument");
-        let sess_id = values[1].get::<u32>().expect("Invalid argument");
-        println!("Requesting fecdec");
-        match make_fec_decoder(&rtpbin, sess_id) {
-            Ok(elem) => Some(elem.to_value()),
-            Err(err) => {
-                gst_element_error!(
-                    rtpbin,
-                    gst::LibraryError::Failed,
-                    ("Failed to make FEC decoder"),
-                    ["{}", err]
-                );
-                None
-            }
-        }
-        });
-    });
-
-    let rtp_caps = gst::Caps::new_simple("application/x-rtp", &[("clock-rate", &48000i32)]);
-    
-    udpsrc.set_property("port", &port.to_value())?;
-    udpsrc.set_property("caps", &rtp_caps.to_value())?;
-    rtpbin.set_property("do-lost", &true.to_value())?;
-    rtpbin.set_property("latency", &latency.to_value())?;
     opusdec.set_property("plc", &true.to_value())?;
     jackaudiosink.set_property("buffer-time", &100000i64.to_value())?;
+
+    if let Some(freq) = config.get::<f64>("tone.freq")? {
+        let pad = get_static_pad(&jackaudiosink, "sink")?;
+        attach_tone_analyzer(&pad, &config, freq)?;
+    }
+
+    if let Some(interval_ms) = config.get::<u64>("latency.interval")? {
+        let pad = get_static_pad(&jackaudiosink, "sink")?;
+        attach_latency_detector(&pad, &pipeline, &config, interval_ms)?;
+    }
+
     let bus = pipeline
         .get_bus()
         .expect("Pipeline without bus. Shouldn't happen!");
@@ -250,9 +417,114 @@ fn example_main() -> Result<(), Error> {
     let ret = pipeline.set_state(gst::State::Playing);
     assert_ne!(ret, gst::StateChangeReturn::Failure);
 
+    // The PCM test has no decoded programme to watch.
+    let silence = match pcm_test {
+        Some(_) => None,
+        None => silence::SilenceDetector::from_config(&config)?.map(|detector| Arc::new(Mutex::new(detector))),
+    };
+    let stats_silence = silence.clone();
+
+    if let Some(switch) = output_switch.clone() {
+        let stable = time::Duration::from_secs(config.get_or("fallback.stable", 10u64)?);
+        let health = link_health.clone();
+        let link_concealments = concealments.clone();
+        thread::spawn(move || {
+            let inputs = switch.lock().unwrap().inputs();
+            let mut failover = Failover::new(inputs, stable);
+            loop {
+                thread::sleep(time::Duration::from_millis(200));
+                // A link whose concealment has been faded out counts as
+                // down, so the next healthy link takes over and the
+                // fallback, the last input and always healthy, only when
+                // none is left.
+                let mut healthy = health
+                    .iter()
+                    .enumerate()
+                    .map(|(i, link)| {
+                        let faded = link_concealments
+                            .get(i)
+                            .map(|concealment| concealment.to_fallback && concealment.is_faded())
+                            .unwrap_or(false);
+                        link.is_healthy() && !faded
+                    })
+                    .collect::<Vec<_>>();
+                healthy.resize(inputs, true);
+                let mut switch = switch.lock().unwrap();
+                let active = switch.active();
+                let choice = failover.choose(&healthy, active);
+                switch.select(choice);
+            }
+        });
+    }
+    let stats_switch = output_switch.clone();
+
+    // The PCM test has no decoded programme to meter either.
+    let loudness = match pcm_test {
+        Some(_) => None,
+        None => Loudness::from_config(&config)?,
+    };
+    if let Some(ref loudness) = loudness {
+        Loudness::attach(loudness, &get_static_pad(&jackaudiosink, "sink")?);
+    }
+
+    let mut control = control::Control::new();
+    if let Some(ref line) = programme_delay {
+        delay::register_commands(&mut control, line);
+    }
+    if let Some(ref loudness) = loudness {
+        loudness::register_commands(&mut control, loudness);
+    }
+    if !control.is_empty() {
+        control.spawn(&config)?;
+    }
+
+    let level_stats = Arc::new(LevelStats::default());
+    let stats_levels = level_stats.clone();
     let pipelineclone = pipeline.clone();
     let stats_thread = thread::spawn(move || {
         loop {
+        for (name, levels) in stats_levels.latest() {
+            println!("Level {}: {}", name, levels);
+        }
+        if let Some(ref counts) = injected_loss {
+            println!("Injected loss: {} of {} packets", counts.lost(), counts.packets());
+        }
+        if let Some(ref stats) = one_way_delay {
+            println!("{}", stats.lock().unwrap().report());
+        }
+        if let Some(ref checker) = pcm_checker {
+            println!("PCM test: {}", checker.lock().unwrap().report());
+        }
+        if let Some(ref line) = programme_delay {
+            println!("Delay: {}", line.lock().unwrap());
+        }
+        if let Some(ref loudness) = loudness {
+            println!("Loudness: {}", loudness);
+        }
+        for (i, concealment) in concealments.iter().enumerate() {
+            println!("Concealment {}: {}", if i == 0 { "primary" } else { "backup" }, concealment);
+        }
+        if let Some(ref switch) = stats_switch {
+            println!("On air: {}", switch.lock().unwrap().active_name());
+        }
+        if let Some(ref silence) = stats_silence {
+            let mut silence = silence.lock().unwrap();
+            silence.check();
+            println!(
+                "Programme: {}, {} silence alarms",
+                if silence.is_silent() { "SILENT" } else { "on air" },
+                silence.alarms()
+            );
+        }
+        if let Some(ref capture) = capture {
+            if let Err(err) = capture.lock().unwrap().flush() {
+                eprintln!("Failed to write capture: {}", err);
+            }
+        }
+        if let Some(ref snapshot) = incident_snapshot {
+            let (unrecovered, description) = unrecovered_packets(&pipelineclone);
+            snapshot.check(unrecovered, &description);
+        }
         match pipelineclone.get_by_name("fecdec") {
             Some(fecdec) => {
                                //  println!("FecDec {:?}", fecdec);
@@ -266,6 +538,10 @@ fn example_main() -> Result<(), Error> {
                         println!("Was not Some");
                         },
         }
+        if let Some(fecdec) = pipelineclone.get_by_name("fecdec1") {
+            println!("Backup recovered packets: {:?}", fecdec.get_property("recovered"));
+            println!("Backup unrecovered packets: {:?}", fecdec.get_property("unrecovered"));
+        }
         match pipelineclone.get_by_name("rtpjitterbuffer0") {
             Some(session) => {
                 let stats = session.get_property("stats").unwrap();
@@ -320,6 +596,23 @@ fn example_main() -> Result<(), Error> {
                     cause: err.get_error(),
                 }.into());
             }
+            MessageView::Element(..) => {
+                if let Some((name, levels)) = level::parse_message(&msg) {
+                    level_stats.update(&name, &levels);
+                    match name.as_str() {
+                        "level" => if let Some(ref silence) = silence {
+                            silence.lock().unwrap().update(&levels);
+                        },
+                        "linklevel" => if let Some(health) = link_health.get(0) {
+                            health.update_levels(&levels);
+                        },
+                        "backuplevel" => if let Some(health) = link_health.get(1) {
+                            health.update_levels(&levels);
+                        },
+                        _ => (),
+                    }
+                }
+            }
             MessageView::StateChanged(s) => match msg.get_src() {
                 Some(element) => if element == pipeline && s.get_current() == gst::State::Playing {
                     eprintln!("PLAYING");
@@ -0,0 +1,407 @@
+use std::cmp::{self, Reverse};
+use std::collections::BinaryHeap;
+use std::env;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+extern crate failure;
+use failure::Error;
+
+#[macro_use]
+extern crate failure_derive;
+
+extern crate wappuradio_tlink;
+use wappuradio_tlink::config;
+use config::{Config, ConfigValueError};
+
+use wappuradio_tlink::loss;
+use loss::{LossModel, Rng};
+
+#[derive(Debug, Fail)]
+#[fail(display = "Usage: {} LISTEN_PORT TARGET_ADDRESS TARGET_PORT [CONFIG]", _0)]
+struct UsageError(String);
+
+#[derive(Debug, Fail)]
+#[fail(display = "Could not resolve {}", _0)]
+struct UnresolvedAddress(String);
+
+#[derive(Debug, Fail)]
+#[fail(display = "Invalid script line {} in {}: {}", _0, _1, _2)]
+struct ScriptError(usize, String, String);
+
+/// Largest datagram read from the sockets, well above any Opus RTP packet.
+const MAX_PACKET_SIZE: usize = 65536;
+
+fn seconds(value: f64) -> Duration {
+    Duration::from_nanos((value * 1e9) as u64)
+}
+
+fn millis(config: &Config, key: &str) -> Result<Duration, Error> {
+    Ok(Duration::from_millis(config.get_or(key, 0u64)?))
+}
+
+/// Parses outage windows written as `START+LENGTH` in seconds, separated
+/// by commas, e.g. `10+2, 60+0.5`.
+fn parse_outages(value: &str) -> Result<Vec<(Duration, Duration)>, Error> {
+    value
+        .split(',')
+        .map(|window| {
+            let mut parts = window.trim().splitn(2, '+');
+            let start = parts.next().and_then(|s| s.trim().parse::<f64>().ok());
+            let length = parts.next().and_then(|s| s.trim().parse::<f64>().ok());
+            match (start, length) {
+                (Some(start), Some(length)) => Ok((seconds(start), seconds(length))),
+                _ => Err(Error::from(ConfigValueError("netem.outages".into(), window.into()))),
+            }
+        })
+        .collect()
+}
+
+/// Link conditions, using the same `impair.*` keys as the in-pipeline
+/// impairment stage plus:
+///
+/// * `netem.rate`: bandwidth cap in kbit/s, 0 for none
+/// * `netem.queue`: ms of backlog allowed behind the cap before tail drop
+/// * `netem.outages`: windows in which everything is lost, see `parse_outages`
+/// * `netem.outage-period`: repeat the outage windows every this many seconds
+/// * `netem.outage`: `true` while the link is down, mainly for scripts
+#[derive(Debug)]
+struct Conditions {
+    drop: f64,
+    duplicate: f64,
+    delay: Duration,
+    jitter: Duration,
+    reorder: bool,
+    rate: u64,
+    queue: Duration,
+    outage: bool,
+    outages: Vec<(Duration, Duration)>,
+    outage_period: Option<Duration>,
+}
+
+impl Conditions {
+    fn from_config(config: &Config) -> Result<Conditions, Error> {
+        let outages = match config.get_str("netem.outages") {
+            Some(value) => parse_outages(value)?,
+            None => Vec::new(),
+        };
+
+        Ok(Conditions {
+            drop: config.get_probability("impair.drop", 0.0)?,
+            duplicate: config.get_probability("impair.duplicate", 0.0)?,
+            delay: millis(config, "impair.delay")?,
+            jitter: millis(config, "impair.jitter")?,
+            reorder: config.get_or("impair.reorder", false)?,
+            rate: config.get_or("netem.rate", 0u64)?,
+            queue: Duration::from_millis(config.get_or("netem.queue", 1000u64)?),
+            outage: config.get_or("netem.outage", false)?,
+            outages,
+            outage_period: config.get::<f64>("netem.outage-period")?.map(seconds),
+        })
+    }
+
+    fn in_outage(&self, elapsed: Duration) -> bool {
+        if self.outage {
+            return true;
+        }
+
+        let t = match self.outage_period {
+            Some(period) if period > Duration::from_secs(0) => {
+                let period_ns = period.as_secs() * 1_000_000_000 + period.subsec_nanos() as u64;
+                let elapsed_ns = elapsed.as_secs() * 1_000_000_000 + elapsed.subsec_nanos() as u64;
+                Duration::from_nanos(elapsed_ns % period_ns)
+            }
+            _ => elapsed,
+        };
+        self.outages
+            .iter()
+            .any(|&(start, length)| t >= start && t < start + length)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    received: usize,
+    forwarded: usize,
+    dropped: usize,
+    outage: usize,
+    queue_full: usize,
+    duplicated: usize,
+}
+
+impl fmt::Display for Counts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} received, {} forwarded, {} dropped, {} in outages, {} over the queue, {} duplicated",
+            self.received, self.forwarded, self.dropped, self.outage, self.queue_full, self.duplicated
+        )
+    }
+}
+
+/// Emulated link in the transmitter to receiver direction.
+struct Link {
+    config: Config,
+    conditions: Conditions,
+    loss: Option<Box<dyn LossModel>>,
+    rng: Rng,
+    start: Instant,
+    link_free: Instant,
+    last_departure: Instant,
+    counts: Counts,
+}
+
+impl Link {
+    fn new(config: Config) -> Result<Link, Error> {
+        let now = Instant::now();
+        Ok(Link {
+            conditions: Conditions::from_config(&config)?,
+            loss: loss::from_config(&config)?,
+            rng: Rng::new(config.get_or("netem.seed", 1u64)?),
+            config,
+            start: now,
+            link_free: now,
+            last_departure: now,
+            counts: Counts::default(),
+        })
+    }
+
+    /// Changes one setting while running. A bad value leaves the previous
+    /// conditions in place.
+    fn apply(&mut self, key: &str, value: &str) -> Result<(), Error> {
+        let mut config = self.config.clone();
+        config.set(key, value);
+
+        let conditions = Conditions::from_config(&config)?;
+        if key.starts_with("loss.") {
+            self.loss = loss::from_config(&config)?;
+        }
+        self.conditions = conditions;
+        self.config = config;
+        Ok(())
+    }
+
+    /// Returns when copies of a packet of `len` bytes that arrived at `now`
+    /// leave the emulated link; none if it is lost.
+    fn schedule(&mut self, now: Instant, len: usize) -> Vec<Instant> {
+        self.counts.received += 1;
+
+        if self.conditions.in_outage(now - self.start) {
+            self.counts.outage += 1;
+            return Vec::new();
+        }
+        let random_drop = self.rng.next_f64() < self.conditions.drop;
+        let model_drop = match self.loss {
+            Some(ref mut model) => model.lose(),
+            None => false,
+        };
+        if random_drop || model_drop {
+            self.counts.dropped += 1;
+            return Vec::new();
+        }
+
+        let mut ready = now;
+        if self.conditions.rate > 0 {
+            let start = cmp::max(now, self.link_free);
+            if start - now > self.conditions.queue {
+                self.counts.queue_full += 1;
+                return Vec::new();
+            }
+            self.link_free = start + Duration::from_nanos(len as u64 * 8_000_000 / self.conditions.rate);
+            ready = self.link_free;
+        }
+
+        let delay = self.conditions.delay;
+        let jitter = self.conditions.jitter;
+        let delay_ns = delay.as_secs() as f64 * 1e9 + delay.subsec_nanos() as f64;
+        let jitter_ns = jitter.as_secs() as f64 * 1e9 + jitter.subsec_nanos() as f64;
+        let offset = delay_ns + (self.rng.next_f64() * 2.0 - 1.0) * jitter_ns;
+        let mut due = ready + Duration::from_nanos(offset.max(0.0) as u64);
+        if !self.conditions.reorder {
+            due = cmp::max(due, self.last_departure);
+            self.last_departure = due;
+        }
+
+        self.counts.forwarded += 1;
+        if self.rng.next_f64() < self.conditions.duplicate {
+            self.counts.duplicated += 1;
+            vec![due, due]
+        } else {
+            vec![due]
+        }
+    }
+}
+
+/// Packets waiting for their departure time, earliest first.
+#[derive(Default)]
+struct Queue {
+    packets: Mutex<BinaryHeap<Reverse<(Instant, u64, Vec<u8>)>>>,
+    ready: Condvar,
+}
+
+impl Queue {
+    fn push(&self, due: Instant, seq: u64, data: Vec<u8>) {
+        self.packets.lock().unwrap().push(Reverse((due, seq, data)));
+        self.ready.notify_one();
+    }
+
+    fn send_loop(&self, socket: &UdpSocket, target: SocketAddr) {
+        let mut packets = self.packets.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let next_due = packets.peek().map(|&Reverse((due, _, _))| due);
+            packets = match next_due {
+                Some(due) if due <= now => {
+                    let Reverse((_, _, data)) = packets.pop().unwrap();
+                    if let Err(err) = socket.send_to(&data, target) {
+                        eprintln!("Failed to send to {}: {}", target, err);
+                    }
+                    packets
+                }
+                Some(due) => self.ready.wait_timeout(packets, due - now).unwrap().0,
+                None => self.ready.wait(packets).unwrap(),
+            };
+        }
+    }
+}
+
+/// Reads `SECONDS key = value` lines that change a setting that many
+/// seconds after start, e.g. `30 netem.outage = true`.
+fn load_script(path: &str) -> Result<Vec<(Duration, String, String)>, Error> {
+    let mut events = Vec::new();
+
+    for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let invalid = || Error::from(ScriptError(i + 1, path.to_string(), line.to_string()));
+        let mut parts = line.splitn(2, char::is_whitespace);
+        let at = parts
+            .next()
+            .and_then(|s| s.parse::<f64>().ok())
+            .ok_or_else(&invalid)?;
+        let setting = parts.next().ok_or_else(&invalid)?;
+        let mut setting = setting.splitn(2, '=');
+        let key = setting.next().map(str::trim).ok_or_else(&invalid)?;
+        let value = setting.next().map(str::trim).ok_or_else(&invalid)?;
+
+        events.push((seconds(at), key.to_string(), value.to_string()));
+    }
+
+    events.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(events)
+}
+
+fn run_script(link: Arc<Mutex<Link>>, events: Vec<(Duration, String, String)>) {
+    let start = link.lock().unwrap().start;
+
+    for (at, key, value) in events {
+        let now = Instant::now();
+        if start + at > now {
+            thread::sleep(start + at - now);
+        }
+
+        match link.lock().unwrap().apply(&key, &value) {
+            Ok(()) => println!("{:?}: {} = {}", at, key, value),
+            Err(err) => eprintln!("{:?}: ignoring {} = {}: {}", at, key, value, err),
+        }
+    }
+}
+
+fn example_main() -> Result<(), Error> {
+    let args: Vec<_> = env::args().collect();
+    if args.len() != 4 && args.len() != 5 {
+        return Err(Error::from(UsageError(args[0].clone())));
+    }
+
+    let listen_port = args[1].parse::<u16>()?;
+    let target_address = format!("{}:{}", args[2], args[3].parse::<u16>()?);
+    let target = target_address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| Error::from(UnresolvedAddress(target_address.clone())))?;
+    let config = Config::from_args(&args, 4)?;
+
+    let script = match config.get_str("netem.script") {
+        Some(path) => load_script(path)?,
+        None => Vec::new(),
+    };
+    let link = Arc::new(Mutex::new(Link::new(config)?));
+    let queue = Arc::new(Queue::default());
+
+    // Transmitter side: packets come in here and replies go back out.
+    let listen_socket = UdpSocket::bind(("0.0.0.0", listen_port))?;
+    // Receiver side: impaired packets go out from here, replies come back.
+    let target_socket = UdpSocket::bind("0.0.0.0:0")?;
+    let client: Arc<Mutex<Option<SocketAddr>>> = Arc::new(Mutex::new(None));
+
+    {
+        let queue = queue.clone();
+        let socket = target_socket.try_clone()?;
+        thread::spawn(move || queue.send_loop(&socket, target));
+    }
+
+    // Replies such as NAT keepalives pass back unimpaired to whoever sent last.
+    {
+        let client = client.clone();
+        let socket = listen_socket.try_clone()?;
+        let target_socket = target_socket.try_clone()?;
+        thread::spawn(move || {
+            let mut data = vec![0u8; MAX_PACKET_SIZE];
+            loop {
+                let len = match target_socket.recv_from(&mut data) {
+                    Ok((len, _)) => len,
+                    Err(err) => {
+                        eprintln!("Failed to receive from {}: {}", target, err);
+                        continue;
+                    }
+                };
+                if let Some(client) = *client.lock().unwrap() {
+                    let _ = socket.send_to(&data[..len], client);
+                }
+            }
+        });
+    }
+
+    if !script.is_empty() {
+        let link = link.clone();
+        thread::spawn(move || run_script(link, script));
+    }
+
+    {
+        let link = link.clone();
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_secs(1));
+            println!("{}", link.lock().unwrap().counts);
+        });
+    }
+
+    println!("Forwarding UDP port {} to {}", listen_port, target);
+    let mut data = vec![0u8; MAX_PACKET_SIZE];
+    let mut seq = 0u64;
+    loop {
+        let (len, from) = listen_socket.recv_from(&mut data)?;
+        let now = Instant::now();
+        *client.lock().unwrap() = Some(from);
+
+        for due in link.lock().unwrap().schedule(now, len) {
+            queue.push(due, seq, data[..len].to_vec());
+            seq += 1;
+        }
+    }
+}
+
+fn main() {
+    match example_main() {
+        Ok(r) => r,
+        Err(e) => eprintln!("Error! {}", e),
+    }
+}
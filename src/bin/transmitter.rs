@@ -1,15 +1,39 @@
-#[macro_use]
 extern crate gstreamer as gst;
 use gst::prelude::*;
+extern crate gstreamer_app as gst_app;
 
 extern crate glib;
 
 use std::error::Error as StdError;
 
-#[path = "../common.rs"]
-mod common;
+extern crate wappuradio_tlink;
+use wappuradio_tlink::common;
+use common::{get_static_pad, make_element};
+
+use wappuradio_tlink::config;
+use config::Config;
+
+use wappuradio_tlink::level;
+use level::LevelStats;
+
+use wappuradio_tlink::link;
+use link::{FecPercentages, TransmitterLink};
+
+use wappuradio_tlink::archive;
+
+use wappuradio_tlink::control;
+
+use wappuradio_tlink::gain;
+
+use wappuradio_tlink::loudness;
+use loudness::Loudness;
+
+use wappuradio_tlink::latency;
+
+use wappuradio_tlink::hdrext;
 
 use std::env;
+use std::sync::Arc;
 use std::time;
 use std::thread;
 
@@ -20,15 +44,7 @@ use failure::Error;
 extern crate failure_derive;
 
 #[derive(Debug, Fail)]
-#[fail(display = "Missing element {}", _0)]
-struct MissingElement(&'static str);
-
-#[derive(Debug, Fail)]
-#[fail(display = "No such pad {} in {}", _0, _1)]
-struct NoSuchPad(&'static str, String);
-
-#[derive(Debug, Fail)]
-#[fail(display = "Usage: {} ADDRESS PORT OPUS_BITRATE OPUS_FRAME_SIZE PERCENTAGE PERCENTAGE_IMPORTANT", _0)]
+#[fail(display = "Usage: {} ADDRESS PORT OPUS_BITRATE OPUS_FRAME_SIZE PERCENTAGE PERCENTAGE_IMPORTANT [CONFIG]", _0)]
 struct UsageError(String);
 
 #[derive(Debug, Fail)]
@@ -41,36 +57,6 @@ struct ErrorMessage {
     cause: glib::Error,
 }
 
-fn make_element<'a, P: Into<Option<&'a str>>>(
-    factory_name: &'static str,
-    element_name: P,
-) -> Result<gst::Element, Error> {
-    match gst::ElementFactory::make(factory_name, element_name.into()) {
-        Some(elem) => Ok(elem),
-        None => Err(Error::from(MissingElement(factory_name))),
-    }
-}
-
-fn get_static_pad(element: &gst::Element, pad_name: &'static str) -> Result<gst::Pad, Error> {
-    match element.get_static_pad(pad_name) {
-        Some(pad) => Ok(pad),
-        None => {
-            let element_name = element.get_name();
-            Err(Error::from(NoSuchPad(pad_name, element_name)))
-        }
-    }
-}
-
-fn get_request_pad(element: &gst::Element, pad_name: &'static str) -> Result<gst::Pad, Error> {
-    match element.get_request_pad(pad_name) {
-        Some(pad) => Ok(pad),
-        None => {
-            let element_name = element.get_name();
-            Err(Error::from(NoSuchPad(pad_name, element_name)))
-        }
-    }
-}
-
 /*
 fn connect_srcbin_pad(src_pad: &gst::Pad, sink: &gst::Element) -> Result<(), Error> {
     let sinkpad = get_static_pad(&sink, "sink")?;
@@ -79,24 +65,12 @@ fn connect_srcbin_pad(src_pad: &gst::Pad, sink: &gst::Element) -> Result<(), Err
     Ok(())
 }*/
 
-fn make_fec_encoder(percentage: u32, percentage_important: u32) -> Result<gst::Element, Error> {
-    let fecenc = make_element("rtpulpfecenc", "fecenc")?;
-
-    fecenc.set_property("pt", &100u32.to_value())?;
-    fecenc.set_property("multipacket", &false.to_value())?;
-//    fecenc.set_property("mux-seq", &true.to_value())?;
-    fecenc.set_property("percentage", &percentage.to_value())?;
-    fecenc.set_property("percentage_important", &percentage_important.to_value())?;
-
-    Ok(fecenc)
-}
-
 fn example_main() -> Result<(), Error> {
     gst::init()?;
 
     let args: Vec<_> = env::args().collect();
 
-    if args.len() != 7 {
+    if args.len() != 7 && args.len() != 8 {
         return Err(Error::from(UsageError(args[0].clone())));
     }
 
@@ -106,23 +80,36 @@ fn example_main() -> Result<(), Error> {
     let opus_frame_size = args[4].parse::<i32>()?;
     let percentage = args[5].parse::<u32>()?;
     let percentage_important = args[6].parse::<u32>()?;
+    let config = Config::from_args(&args, 7)?;
 
     let pipeline = gst::Pipeline::new(None);
-    let rtpbin = make_element("rtpbin", None)?;
     let jackaudiosrc = make_element("jackaudiosrc", None)?;
     let audioconvert = make_element("audioconvert", None)?;
+    let input_stage = gain::InputStage::from_config(&config)?;
+    let input_elements = input_stage.make_elements()?;
+    let level = level::make_level(&config, "level")?;
     let queue1 = make_element("queue", None)?;
     let opusenc = make_element("opusenc", None)?;
     let queue2 = make_element("queue", None)?;
     let rtpopuspay = make_element("rtpopuspay", None)?;
-    let udpsink = make_element("udpsink", None)?;
 
-    pipeline.add_many(&[&jackaudiosrc, &audioconvert, &queue1, &opusenc, &queue2, &rtpopuspay, &rtpbin, &udpsink])?;
+    pipeline.add_many(&[&jackaudiosrc, &audioconvert, &input_elements, &level, &queue1, &opusenc, &queue2, &rtpopuspay])?;
     
     jackaudiosrc.link(&audioconvert)?;
-    audioconvert.link(&queue1)?;
+    audioconvert.link(&input_elements)?;
+    input_elements.link(&level)?;
+    level.link(&queue1)?;
     queue1.link(&opusenc)?;
-    opusenc.link(&rtpopuspay)?;
+    match archive::Archive::local_copy(&config, port)? {
+        Some(recording) => {
+            let tee = make_element("tee", "recordtee")?;
+            let recorder = recording.make_recorder()?;
+            pipeline.add_many(&[&tee, &recorder])?;
+            gst::Element::link_many(&[&opusenc, &tee, &rtpopuspay])?;
+            tee.link(&recorder)?;
+        }
+        None => opusenc.link(&rtpopuspay)?,
+    }
     rtpopuspay.link(&queue2)?;
 
 
@@ -146,33 +133,12 @@ fn example_main() -> Result<(), Error> {
     enc.link(&pay)?;
     pay.link(&q2)?;
     */
-    rtpbin.connect("request-fec-encoder", false, move |values| {
-        let rtpbin = values[0].get::<gst::Element>().expect("Invalid argument");
-
-        match make_fec_encoder(percentage, percentage_important) {
-            Ok(elem) => Some(elem.to_value()),
-            Err(err) => {
-                gst_element_error!(
-                    rtpbin,
-                    gst::LibraryError::Failed,
-                    ("Failed to make FEC encoder"),
-                    ["{}", err]
-                );
-                None
-            }
-        }
-    })?;
-
-    //Are these linkings necessary for us?
-    
-    let srcpad = get_static_pad(&queue2, "src")?;
-    let sinkpad = get_request_pad(&rtpbin, "send_rtp_sink_0")?;
-    srcpad.link(&sinkpad).into_result()?;
-
-    
-    let srcpad = get_static_pad(&rtpbin, "send_rtp_src_0")?;
-    let sinkpad = get_static_pad(&udpsink, "sink")?;
-    srcpad.link(&sinkpad).into_result()?;
+    let fec = FecPercentages {
+        percentage,
+        important: percentage_important,
+    };
+    let TransmitterLink { capture, .. } =
+        link::make_transmitter(&pipeline, &config, &queue2, address, port, false, fec)?;
     
    // let srcpad = get_static_pad(&jackaudiosrc, "src")?;
    // let sinkpad = get_static_pad(&audioconvert, "sink")?;
@@ -202,12 +168,19 @@ fn example_main() -> Result<(), Error> {
     opusenc.set_property("bitrate", &opus_bitrate.to_value())?;
     opusenc.set_property("frame-size", &frame_size_as_value)?;
 //    opusenc.set_property("max-payload-size", &512u32.to_value())?;
-    udpsink.set_property("host", &address.to_value())?;
-    udpsink.set_property("sync", &false.to_value())?;
-    udpsink.set_property("port", &port.to_value())?;
  //   src.set_property("caps", &audio_caps.to_value())?;
  //   src.set_property("uri", &uri.to_value())?;
 
+    if let Some(ids) = hdrext::ExtensionIds::from_config(&config)? {
+        let pad = get_static_pad(&rtpopuspay, "src")?;
+        hdrext::attach_stamper(&pad, ids);
+    }
+
+    if let Some(interval_ms) = config.get::<u64>("latency.interval")? {
+        let pad = get_static_pad(&audioconvert, "src")?;
+        latency::attach_pulse_inserter(&pad, interval_ms * 1_000_000);
+    }
+
     let bus = pipeline
         .get_bus()
         .expect("Pipeline without bus. Shouldn't happen!");
@@ -215,9 +188,36 @@ fn example_main() -> Result<(), Error> {
     let ret = pipeline.set_state(gst::State::Playing);
     assert_ne!(ret, gst::StateChangeReturn::Failure);
 
+    // Metered after the gain and dynamics, as it is encoded.
+    let loudness = Loudness::from_config(&config)?;
+    if let Some(ref loudness) = loudness {
+        Loudness::attach(loudness, &get_static_pad(&level, "sink")?);
+    }
+
+    let mut control = control::Control::new();
+    gain::register_commands(&mut control, &input_stage);
+    if let Some(ref loudness) = loudness {
+        loudness::register_commands(&mut control, loudness);
+    }
+    control.spawn(&config)?;
+
+    let level_stats = Arc::new(LevelStats::default());
+    let stats_levels = level_stats.clone();
     let pipelineclone = pipeline.clone();
     let _stats_thread = thread::spawn(move || {
         loop {
+        println!("Input: {}", input_stage);
+        for (name, levels) in stats_levels.latest() {
+            println!("Level {}: {}", name, levels);
+        }
+        if let Some(ref capture) = capture {
+            if let Err(err) = capture.lock().unwrap().flush() {
+                eprintln!("Failed to write capture: {}", err);
+            }
+        }
+        if let Some(ref loudness) = loudness {
+            println!("Loudness: {}", loudness);
+        }
         match pipelineclone.get_by_name("fecenc") {
             Some(fecenc) => {
                                //  println!("FecDec {:?}", fecdec);
@@ -252,6 +252,11 @@ fn example_main() -> Result<(), Error> {
                     cause: err.get_error(),
                 }.into());
             }
+            MessageView::Element(..) => {
+                if let Some((name, levels)) = level::parse_message(&msg) {
+                    level_stats.update(&name, &levels);
+                }
+            }
             MessageView::StateChanged(s) => match msg.get_src() {
                 Some(element) => if element == pipeline && s.get_current() == gst::State::Playing {
                     eprintln!("PLAYING");
@@ -1,13 +1,34 @@
 #[macro_use]
 extern crate gstreamer as gst;
 use gst::prelude::*;
+extern crate gstreamer_app as gst_app;
 
 extern crate glib;
 
 use std::error::Error as StdError;
 
-#[path = "../common.rs"]
-mod common;
+extern crate wappuradio_tlink;
+use wappuradio_tlink::common;
+use common::{get_request_pad, get_static_pad, make_element};
+
+use wappuradio_tlink::config;
+use config::Config;
+
+use wappuradio_tlink::srtp;
+
+use wappuradio_tlink::transport;
+
+use wappuradio_tlink::rtpdump;
+
+use wappuradio_tlink::level;
+
+use wappuradio_tlink::impairment;
+
+use wappuradio_tlink::latency;
+
+use wappuradio_tlink::hdrext;
+
+use wappuradio_tlink::prbs;
 
 use std::env;
 
@@ -18,15 +39,7 @@ use failure::Error;
 extern crate failure_derive;
 
 #[derive(Debug, Fail)]
-#[fail(display = "Missing element {}", _0)]
-struct MissingElement(&'static str);
-
-#[derive(Debug, Fail)]
-#[fail(display = "No such pad {} in {}", _0, _1)]
-struct NoSuchPad(&'static str, String);
-
-#[derive(Debug, Fail)]
-#[fail(display = "Usage: {} ADDRESS PORT OPUS_BITRATE OPUS_FRAME_SIZE PERCENTAGE PERCENTAGE_IMPORTANT WAVE FREQ", _0)]
+#[fail(display = "Usage: {} ADDRESS PORT OPUS_BITRATE OPUS_FRAME_SIZE PERCENTAGE PERCENTAGE_IMPORTANT WAVE FREQ [CONFIG]", _0)]
 struct UsageError(String);
 
 #[derive(Debug, Fail)]
@@ -55,36 +68,6 @@ enum GstAudioTestSrcWave {
    GST_AUDIO_TEST_SRC_WAVE_VIOLET_NOISE
 }
 */
-fn make_element<'a, P: Into<Option<&'a str>>>(
-    factory_name: &'static str,
-    element_name: P,
-) -> Result<gst::Element, Error> {
-    match gst::ElementFactory::make(factory_name, element_name.into()) {
-        Some(elem) => Ok(elem),
-        None => Err(Error::from(MissingElement(factory_name))),
-    }
-}
-
-fn get_static_pad(element: &gst::Element, pad_name: &'static str) -> Result<gst::Pad, Error> {
-    match element.get_static_pad(pad_name) {
-        Some(pad) => Ok(pad),
-        None => {
-            let element_name = element.get_name();
-            Err(Error::from(NoSuchPad(pad_name, element_name)))
-        }
-    }
-}
-
-fn get_request_pad(element: &gst::Element, pad_name: &'static str) -> Result<gst::Pad, Error> {
-    match element.get_request_pad(pad_name) {
-        Some(pad) => Ok(pad),
-        None => {
-            let element_name = element.get_name();
-            Err(Error::from(NoSuchPad(pad_name, element_name)))
-        }
-    }
-}
-
 fn connect_decodebin_pad(src_pad: &gst::Pad, sink: &gst::Element) -> Result<(), Error> {
     let sinkpad = get_static_pad(&sink, "sink")?;
     src_pad.link(&sinkpad).into_result()?;
@@ -107,7 +90,7 @@ fn example_main() -> Result<(), Error> {
     gst::init()?;
     let args: Vec<_> = env::args().collect();
 
-    if args.len() != 9 {
+    if args.len() != 9 && args.len() != 10 {
         return Err(Error::from(UsageError(args[0].clone())));
     }
 
@@ -119,21 +102,37 @@ fn example_main() -> Result<(), Error> {
     let percentage_important = args[6].parse::<u32>()?;
     let wave = args[7].parse::<i32>()?;
     let freq = args[8].parse::<f64>()?;
+    let config = Config::from_args(&args, 9)?;
 
     let pipeline = gst::Pipeline::new(None);
     let rtpbin = make_element("rtpbin", None)?;
     let audiotestsrc = make_element("audiotestsrc", None)?;
     let audioconvert = make_element("audioconvert", None)?;
+    let level = level::make_level(&config, "level")?;
     let opusenc = make_element("opusenc", None)?;
     let rtpopuspay = make_element("rtpopuspay", None)?;
-    let udpsink = make_element("udpsink", None)?;
+    let netsink = transport::make_transmitter_sink(&config, address, port, true)?;
 
-    pipeline.add_many(&[&audiotestsrc, &audioconvert, &opusenc, &rtpopuspay, &rtpbin, &udpsink])?;
+    pipeline.add_many(&[&rtpbin, &netsink])?;
     //Check if sink needs to be connected later
   //  gst::Element::link_many(&[&audiotestsrc, &audioconvert, &opusenc, &rtpopuspay, &udpsink])?;
 
-    audioconvert.link(&opusenc)?;
-    opusenc.link(&rtpopuspay)?;
+    // The PCM test replaces the whole audio chain.
+    let payloader = match prbs::PcmTest::from_config(&config)? {
+        Some(test) => {
+            let source = prbs::make_source(&test)?;
+            pipeline.add(&source)?;
+            source
+        }
+        None => {
+            pipeline.add_many(&[&audiotestsrc, &audioconvert, &level, &opusenc, &rtpopuspay])?;
+            audiotestsrc.link(&audioconvert)?;
+            audioconvert.link(&level)?;
+            level.link(&opusenc)?;
+            opusenc.link(&rtpopuspay)?;
+            rtpopuspay.clone()
+        }
+    };
     /*
     let src = make_element("uridecodebin", None)?;
     let conv = make_element("videoconvert", None)?;
@@ -168,13 +167,53 @@ fn example_main() -> Result<(), Error> {
         }
     })?;
 
-    let srcpad = get_static_pad(&rtpopuspay, "src")?;
+    if let Some(srtp) = srtp::SrtpSettings::from_config(&config)? {
+        let key = srtp.key.clone();
+        rtpbin.connect("request-rtp-encoder", false, move |values| {
+            let rtpbin = values[0].get::<gst::Element>().expect("Invalid argument");
+
+            match srtp::make_encoder(&key) {
+                Ok(elem) => Some(elem.to_value()),
+                Err(err) => {
+                    gst_element_error!(
+                        rtpbin,
+                        gst::LibraryError::Failed,
+                        ("Failed to make SRTP encoder"),
+                        ["{}", err]
+                    );
+                    None
+                }
+            }
+        })?;
+
+        let pipelineclone = pipeline.clone();
+        srtp.watch_key_file(move |key| {
+            if let Some(srtpenc) = pipelineclone.get_by_name("srtpenc") {
+                if let Err(err) = srtp::set_encoder_key(&srtpenc, &key) {
+                    eprintln!("Failed to rotate SRTP key: {}", err);
+                }
+            }
+        });
+    }
+
+    let srcpad = get_static_pad(&payloader, "src")?;
     let sinkpad = get_request_pad(&rtpbin, "send_rtp_sink_0")?;
     srcpad.link(&sinkpad).into_result()?;
 
     
     let srcpad = get_static_pad(&rtpbin, "send_rtp_src_0")?;
-    let sinkpad = get_static_pad(&udpsink, "sink")?;
+    if let Some(path) = config.get_str("capture.file") {
+        rtpdump::attach_capture(&srcpad, path, port)?;
+    }
+    let rtp_sink = match impairment::make_stage(&config)? {
+        Some(netsim) => {
+            pipeline.add(&netsim)?;
+            netsim.link(&netsink)?;
+            netsim
+        }
+        None => netsink.clone(),
+    };
+    let sinkpad = get_static_pad(&rtp_sink, "sink")?;
     srcpad.link(&sinkpad).into_result()?;
     
     let convclone = audioconvert.clone();
@@ -192,7 +231,6 @@ fn example_main() -> Result<(), Error> {
             }
         }
     });*/
-    audiotestsrc.link(&audioconvert)?;
 
     //Are these linkings necessary for us?
     
@@ -202,7 +240,7 @@ fn example_main() -> Result<(), Error> {
 
     
     let srcpad = get_static_pad(&rtpbin, "send_rtp_src_0")?;
-    let sinkpad = get_static_pad(&udpsink, "sink")?;
+    let sinkpad = get_static_pad(&netsink, "sink")?;
     srcpad.link(&sinkpad).into_result()?;
     *//*
     let convclone = conv.clone();
@@ -230,12 +268,19 @@ fn example_main() -> Result<(), Error> {
     audiotestsrc.set_property("freq", &freq.to_value())?;
     opusenc.set_property("bitrate", &opus_bitrate.to_value())?;
     opusenc.set_property("frame-size", &frame_size_as_value)?;
-    udpsink.set_property("host", &address.to_value())?;
-    udpsink.set_property("sync", &true.to_value())?;
-    udpsink.set_property("port", &port.to_value())?;
 //    src.set_property("caps", &video_caps.to_value())?;
  //   src.set_property("uri", &uri.to_value())?;
 
+    if let Some(ids) = hdrext::ExtensionIds::from_config(&config)? {
+        let pad = get_static_pad(&payloader, "src")?;
+        hdrext::attach_stamper(&pad, ids);
+    }
+
+    if let Some(interval_ms) = config.get::<u64>("latency.interval")? {
+        let pad = get_static_pad(&audioconvert, "src")?;
+        latency::attach_pulse_inserter(&pad, interval_ms * 1_000_000);
+    }
+
     let bus = pipeline
         .get_bus()
         .expect("Pipeline without bus. Shouldn't happen!");
@@ -258,6 +303,11 @@ fn example_main() -> Result<(), Error> {
                     cause: err.get_error(),
                 }.into());
             }
+            MessageView::Element(..) => {
+                if let Some((name, levels)) = level::parse_message(&msg) {
+                    println!("Level {}: {}", name, levels);
+                }
+            }
             MessageView::StateChanged(s) => match msg.get_src() {
                 Some(element) => if element == pipeline && s.get_current() == gst::State::Playing {
                     eprintln!("PLAYING");
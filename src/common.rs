@@ -1,3 +1,119 @@
+use gst;
+use gst::prelude::*;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use failure::Error;
+
+#[derive(Debug, Fail)]
+#[fail(display = "Missing element {}", _0)]
+pub struct MissingElement(pub &'static str);
+
+#[derive(Debug, Fail)]
+#[fail(display = "No such pad {} in {}", _0, _1)]
+pub struct NoSuchPad(pub &'static str, pub String);
+
+pub fn make_element<'a, P: Into<Option<&'a str>>>(
+    factory_name: &'static str,
+    element_name: P,
+) -> Result<gst::Element, Error> {
+    match gst::ElementFactory::make(factory_name, element_name.into()) {
+        Some(elem) => Ok(elem),
+        None => Err(Error::from(MissingElement(factory_name))),
+    }
+}
+
+pub fn get_static_pad(element: &gst::Element, pad_name: &'static str) -> Result<gst::Pad, Error> {
+    match element.get_static_pad(pad_name) {
+        Some(pad) => Ok(pad),
+        None => {
+            let element_name = element.get_name();
+            Err(Error::from(NoSuchPad(pad_name, element_name)))
+        }
+    }
+}
+
+pub fn get_request_pad(element: &gst::Element, pad_name: &'static str) -> Result<gst::Pad, Error> {
+    match element.get_request_pad(pad_name) {
+        Some(pad) => Ok(pad),
+        None => {
+            let element_name = element.get_name();
+            Err(Error::from(NoSuchPad(pad_name, element_name)))
+        }
+    }
+}
+
+/// Puts `elements` into a bin, links them in order and ghosts the sink pad
+/// of the first and the src pad of the last if they have one.
+pub fn make_bin(elements: &[&gst::Element]) -> Result<gst::Element, Error> {
+    let bin = gst::Bin::new(None);
+    bin.add_many(elements)?;
+    gst::Element::link_many(elements)?;
+
+    let first = elements[0].get_static_pad("sink");
+    let last = elements[elements.len() - 1].get_static_pad("src");
+    for (name, target) in vec![("sink", first), ("src", last)] {
+        if let Some(target) = target {
+            let ghost = gst::GhostPad::new(name, &target)
+                .ok_or_else(|| Error::from(NoSuchPad(name, bin.get_name())))?;
+            bin.add_pad(&ghost)?;
+        }
+    }
+
+    Ok(bin.upcast::<gst::Element>())
+}
+
+/// Current running time of the pipeline `element` is in, in nanoseconds.
+/// Live sources made in Rust timestamp their buffers from it.
+pub fn running_time(element: &gst::Element) -> u64 {
+    let now = element.get_clock().and_then(|clock| clock.get_time().nseconds());
+    let base = element.get_base_time().nseconds();
+    match (now, base) {
+        (Some(now), Some(base)) if now > base => now - base,
+        _ => 0,
+    }
+}
+
+/// Start and duration in ns of a GAP event. The RTP depayloaders turn the
+/// jitterbuffer's lost packet events into these, so they are what reaches
+/// a decoder for every lost packet.
+pub fn gap_span(event: &gst::EventRef) -> Option<(u64, u64)> {
+    match event.view() {
+        gst::EventView::Gap(gap) => {
+            let (start, duration) = gap.get();
+            Some((start.nseconds()?, duration.nseconds()?))
+        }
+        _ => None,
+    }
+}
+
+/// `time` as a UTC timestamp like `20180612T143005Z`, for file names.
+pub fn utc_timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+
+    // Days to civil date, from Howard Hinnant's date algorithms.
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
 /// macOS has a specific requirement that there must be a run loop running
 /// on the main thread in order to open windows and use OpenGL.
 
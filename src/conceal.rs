@@ -0,0 +1,239 @@
+use gst;
+use gst::prelude::*;
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use failure::Error;
+
+use audio;
+use common::{gap_span, get_static_pad};
+use config::{Config, ConfigValueError};
+
+/// Lost packets remembered at most, far more than one jitterbuffer's worth.
+const MAX_SPANS: usize = 256;
+
+#[derive(Debug, Default)]
+struct FadeState {
+    /// Lost spans announced by the jitterbuffer as `(start, end)` in ns.
+    spans: VecDeque<(u64, u64)>,
+    concealing_since: Option<u64>,
+    /// Whether the concealment has gone on too long and is faded out, or
+    /// fading out. Fading back in already counts as not faded.
+    faded: bool,
+    gain: f32,
+    fades: usize,
+}
+
+/// Fades out the decoder's packet loss concealment once it has gone on for
+/// longer than `conceal.max` ms, instead of letting it buzz on, and fades
+/// back in when real audio resumes. Fades take `conceal.fade` ms (default
+/// 50). With `conceal.target = fallback` the receiver also crossfades away
+/// from the link for as long as the concealment lasts, to the backup link
+/// if it is healthy and otherwise to the fallback source; the default
+/// `silence` only fades out.
+#[derive(Debug)]
+pub struct Concealment {
+    max_ns: u64,
+    fade_ns: u64,
+    pub to_fallback: bool,
+    state: Mutex<FadeState>,
+}
+
+impl Concealment {
+    pub fn from_config(config: &Config) -> Result<Option<Arc<Concealment>>, Error> {
+        let max_ms = match config.get::<u64>("conceal.max")? {
+            Some(max_ms) => max_ms,
+            None => return Ok(None),
+        };
+        let to_fallback = match config.get_str("conceal.target") {
+            None | Some("silence") => false,
+            Some("fallback") => true,
+            Some(other) => return Err(Error::from(ConfigValueError("conceal.target".into(), other.into()))),
+        };
+
+        Ok(Some(Arc::new(Concealment {
+            max_ns: max_ms * 1_000_000,
+            fade_ns: config.get_or("conceal.fade", 50u64)?.max(1) * 1_000_000,
+            to_fallback,
+            state: Mutex::new(FadeState {
+                gain: 1.0,
+                ..FadeState::default()
+            }),
+        })))
+    }
+
+    fn lost(&self, start: u64, duration: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.spans.push_back((start, start + duration));
+        if state.spans.len() > MAX_SPANS {
+            state.spans.pop_front();
+        }
+    }
+
+    /// Applies the fade to a block of decoded audio starting at `pts`.
+    fn process(&self, pts: u64, block: &mut audio::AudioBlock) {
+        let mut state = self.state.lock().unwrap();
+        while state.spans.front().map(|&(_, end)| end <= pts).unwrap_or(false) {
+            state.spans.pop_front();
+        }
+        let concealed = state.spans.iter().any(|&(start, end)| start <= pts && pts < end);
+
+        let duration = block.frames() as u64 * 1_000_000_000 / block.rate as u64;
+        state.concealing_since = match (concealed, state.concealing_since) {
+            (false, _) => None,
+            (true, None) => Some(pts),
+            (true, since) => since,
+        };
+        let fade_out = match state.concealing_since {
+            Some(since) => pts + duration - since > self.max_ns,
+            None => false,
+        };
+        if fade_out && !state.faded {
+            state.fades += 1;
+        }
+        state.faded = fade_out;
+
+        let target = if fade_out { 0.0 } else { 1.0 };
+        let step = (1_000_000_000.0 / (self.fade_ns as f64 * block.rate as f64)) as f32;
+        let mut gain = state.gain;
+        for frame in block.samples.chunks_mut(block.channels) {
+            gain = if gain < target {
+                (gain + step).min(target)
+            } else {
+                (gain - step).max(target)
+            };
+            for sample in frame {
+                *sample *= gain;
+            }
+        }
+        state.gain = gain;
+    }
+
+    /// Whether the concealment has gone on too long right now.
+    pub fn is_faded(&self) -> bool {
+        self.state.lock().unwrap().faded
+    }
+}
+
+impl fmt::Display for Concealment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        write!(
+            f,
+            "{} long concealments faded{}",
+            state.fades,
+            if state.faded { ", faded now" } else { "" }
+        )
+    }
+}
+
+/// Watches the gaps the depayloader sends `decoder` for lost packets and
+/// fades the audio coming out of it.
+pub fn attach(concealment: &Arc<Concealment>, decoder: &gst::Element) -> Result<(), Error> {
+    let event_concealment = concealment.clone();
+    get_static_pad(decoder, "sink")?.add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+        if let Some(gst::PadProbeData::Event(ref event)) = info.data {
+            if let Some((start, duration)) = gap_span(event) {
+                event_concealment.lost(start, duration);
+            }
+        }
+        gst::PadProbeReturn::Ok
+    });
+
+    let concealment = concealment.clone();
+    get_static_pad(decoder, "src")?.add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+        if let Some(gst::PadProbeData::Buffer(ref mut buffer)) = info.data {
+            let buffer = buffer.make_mut();
+            if let (Some(mut block), Some(pts)) = (audio::read_block(pad, buffer), buffer.get_pts().nseconds()) {
+                concealment.process(pts, &mut block);
+                audio::write_block(pad, buffer, &block.samples);
+            }
+        }
+        gst::PadProbeReturn::Ok
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+    /// 10 ms blocks.
+    const BLOCK_NS: u64 = 10_000_000;
+
+    fn concealment(max_ms: &str) -> Arc<Concealment> {
+        let mut config = Config::default();
+        config.set("conceal.max", max_ms);
+        config.set("conceal.target", "fallback");
+        Concealment::from_config(&config).unwrap().unwrap()
+    }
+
+    /// Processes a block of ones starting at `ms` and returns its last
+    /// sample.
+    fn process(concealment: &Concealment, ms: u64) -> f32 {
+        let mut block = audio::AudioBlock {
+            rate: RATE,
+            channels: 2,
+            samples: vec![1.0; RATE as usize / 100 * 2],
+        };
+        concealment.process(ms * 1_000_000, &mut block);
+        *block.samples.last().unwrap()
+    }
+
+    #[test]
+    fn short_concealment_is_left_alone() {
+        let concealment = concealment("100");
+        concealment.lost(0, 80 * 1_000_000);
+        for ms in (0..200).step_by(10) {
+            assert_eq!(process(&concealment, ms), 1.0);
+            assert!(!concealment.is_faded());
+        }
+        assert_eq!(concealment.to_string(), "0 long concealments faded");
+    }
+
+    #[test]
+    fn long_concealment_fades_out_and_back() {
+        let concealment = concealment("100");
+        // The jitterbuffer announces each lost packet on its own.
+        for ms in (0..300).step_by(20) {
+            concealment.lost(ms * 1_000_000, 2 * BLOCK_NS);
+        }
+
+        for ms in (0..100).step_by(10) {
+            assert_eq!(process(&concealment, ms), 1.0);
+            assert!(!concealment.is_faded());
+        }
+        for ms in (100..300).step_by(10) {
+            let gain = process(&concealment, ms);
+            assert!(concealment.is_faded());
+            if ms >= 150 {
+                assert_eq!(gain, 0.0);
+            }
+        }
+        assert_eq!(concealment.to_string(), "1 long concealments faded, faded now");
+
+        // Real audio fades back in over the 50 ms fade.
+        assert!(process(&concealment, 300) < 1.0);
+        assert!(!concealment.is_faded());
+        for ms in (310..400).step_by(10) {
+            process(&concealment, ms);
+        }
+        assert_eq!(process(&concealment, 400), 1.0);
+        assert_eq!(concealment.to_string(), "1 long concealments faded");
+    }
+
+    #[test]
+    fn counts_each_long_concealment_once() {
+        let concealment = concealment("50");
+        concealment.lost(0, 200 * 1_000_000);
+        concealment.lost(500 * 1_000_000, 200 * 1_000_000);
+        for ms in (0..1000).step_by(10) {
+            process(&concealment, ms);
+        }
+        assert_eq!(concealment.to_string(), "2 long concealments faded");
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::str::FromStr;
+
+use failure::Error;
+
+#[derive(Debug, Fail)]
+#[fail(display = "Invalid config line {} in {}: {}", _0, _1, _2)]
+pub struct ConfigSyntaxError(usize, String, String);
+
+#[derive(Debug, Fail)]
+#[fail(display = "Invalid value for {}: {}", _0, _1)]
+pub struct ConfigValueError(pub String, pub String);
+
+/// Optional settings that do not fit on the command line, read from a file of
+/// `key = value` lines. Empty lines and lines starting with `#` are ignored.
+#[derive(Debug, Default, Clone)]
+pub struct Config {
+    values: HashMap<String, String>,
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Config, Error> {
+        let file = File::open(path)?;
+        let mut values = HashMap::new();
+
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match line.find('=') {
+                Some(pos) => {
+                    let key = line[..pos].trim().to_string();
+                    let value = line[pos + 1..].trim().to_string();
+                    values.insert(key, value);
+                }
+                None => {
+                    return Err(Error::from(ConfigSyntaxError(
+                        i + 1,
+                        path.to_string(),
+                        line.to_string(),
+                    )))
+                }
+            }
+        }
+
+        Ok(Config { values })
+    }
+
+    /// Loads the config file given as the optional last command line argument.
+    pub fn from_args(args: &[String], index: usize) -> Result<Config, Error> {
+        match args.get(index) {
+            Some(path) => Config::load(path),
+            None => Ok(Config::default()),
+        }
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.values.insert(key.to_string(), value.to_string());
+    }
+
+    /// A copy of the config in which every `<section>.<key>` overrides
+    /// `<key>`, for settings that differ between links, like the backup
+    /// link's `backup.nat.peer`.
+    pub fn with_section(&self, section: &str) -> Config {
+        let prefix = format!("{}.", section);
+        let mut values = self.values.clone();
+        for (key, value) in &self.values {
+            if key.starts_with(&prefix) {
+                values.insert(key[prefix.len()..].to_string(), value.clone());
+            }
+        }
+        Config { values }
+    }
+
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(|s| s.as_str())
+    }
+
+    pub fn get<T: FromStr>(&self, key: &str) -> Result<Option<T>, Error> {
+        match self.values.get(key) {
+            Some(value) => match value.parse::<T>() {
+                Ok(v) => Ok(Some(v)),
+                Err(_) => Err(Error::from(ConfigValueError(key.to_string(), value.clone()))),
+            },
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_or<T: FromStr>(&self, key: &str, default: T) -> Result<T, Error> {
+        Ok(self.get(key)?.unwrap_or(default))
+    }
+
+    /// A probability from 0 to 1.
+    pub fn get_probability(&self, key: &str, default: f64) -> Result<f64, Error> {
+        let value = self.get_or(key, default)?;
+        if value < 0.0 || value > 1.0 {
+            return Err(Error::from(ConfigValueError(key.into(), value.to_string())));
+        }
+        Ok(value)
+    }
+}
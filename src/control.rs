@@ -0,0 +1,113 @@
+use std::collections::BTreeMap;
+use std::io::{self, BufRead};
+use std::net::UdpSocket;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+
+use failure::Error;
+
+use config::Config;
+
+#[derive(Debug, Fail)]
+#[fail(display = "Unknown command {}", _0)]
+pub struct UnknownCommand(pub String);
+
+#[derive(Debug, Fail)]
+#[fail(display = "Invalid argument {}", _0)]
+pub struct InvalidArgument(pub String);
+
+/// Handles the words after the command name and returns the reply.
+type Handler = Box<dyn Fn(&[&str]) -> Result<String, Error> + Send + Sync>;
+
+/// Runtime commands, one per line, like `delay dump`. Commands are read
+/// from stdin and, if `control.port` is set, from UDP datagrams on that
+/// port of `control.address` (default 127.0.0.1). Replies go to stdout or
+/// back to the sender of the datagram.
+#[derive(Default)]
+pub struct Control {
+    handlers: BTreeMap<String, Handler>,
+}
+
+impl Control {
+    pub fn new() -> Control {
+        Control::default()
+    }
+
+    pub fn register<F>(&mut self, command: &str, handler: F)
+    where
+        F: Fn(&[&str]) -> Result<String, Error> + Send + Sync + 'static,
+    {
+        self.handlers.insert(command.to_string(), Box::new(handler));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handlers.is_empty()
+    }
+
+    fn handle(&self, line: &str) -> String {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let result = match words.split_first() {
+            None => return String::new(),
+            Some((&"help", _)) => Ok(format!(
+                "Commands: {}",
+                self.handlers.keys().cloned().collect::<Vec<_>>().join(", ")
+            )),
+            Some((command, args)) => match self.handlers.get(*command) {
+                Some(handler) => handler(args),
+                None => Err(Error::from(UnknownCommand(command.to_string()))),
+            },
+        };
+        match result {
+            Ok(reply) => reply,
+            Err(err) => format!("Error: {}", err),
+        }
+    }
+
+    /// Starts reading commands in the background.
+    pub fn spawn(self, config: &Config) -> Result<(), Error> {
+        let control = Arc::new(self);
+
+        if let Some(port) = config.get::<u16>("control.port")? {
+            let address = config.get_str("control.address").unwrap_or("127.0.0.1");
+            let socket = UdpSocket::bind((address, port))?;
+            let control = control.clone();
+            thread::spawn(move || {
+                let mut data = vec![0u8; 1500];
+                loop {
+                    let (len, peer) = match socket.recv_from(&mut data) {
+                        Ok(received) => received,
+                        Err(err) => {
+                            eprintln!("Failed to receive command: {}", err);
+                            continue;
+                        }
+                    };
+                    let line = String::from_utf8_lossy(&data[..len]).into_owned();
+                    let reply = control.handle(&line);
+                    println!("Command from {}: {}: {}", peer, line.trim(), reply);
+                    let _ = socket.send_to(format!("{}\n", reply).as_bytes(), peer);
+                }
+            });
+        }
+
+        thread::spawn(move || {
+            let stdin = io::stdin();
+            for line in stdin.lock().lines() {
+                match line {
+                    Ok(line) => println!("{}", control.handle(&line)),
+                    Err(_) => return,
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
+
+/// Parses the single argument of a command.
+pub fn parse_arg<T: FromStr>(args: &[&str]) -> Result<T, Error> {
+    match args {
+        [arg] => arg.parse::<T>().map_err(|_| Error::from(InvalidArgument(arg.to_string()))),
+        _ => Err(Error::from(InvalidArgument(args.join(" ")))),
+    }
+}
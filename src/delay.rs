@@ -0,0 +1,491 @@
+use gst;
+use gst::prelude::*;
+use gst_app;
+
+use byte_slice_cast::*;
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+use failure::Error;
+
+use common::{make_bin, make_element, running_time};
+use config::{Config, ConfigValueError};
+use control::{Control, UnknownCommand};
+use selector::make_input_chain;
+
+const RATE: usize = 48000;
+const CHANNELS: usize = 2;
+/// Frames the delay line puts out per buffer, 10 ms.
+const CHUNK: usize = RATE / 100;
+/// Frames of programme in one overlap-add frame. Frames overlap by half,
+/// so every chunk put out is the second half of one frame added to the
+/// first half of the next.
+const FRAME: usize = 2 * CHUNK;
+/// How far a frame may be taken from its nominal position to line up with
+/// the one before it, 5 ms.
+const SEARCH: usize = RATE / 200;
+/// Delay error, in seconds, left alone by the drift correction.
+const DRIFT_TOLERANCE: f64 = 0.02;
+/// Speed change per second of delay error.
+const DRIFT_GAIN: f64 = 0.1;
+/// Largest speed change of the drift correction, 0.5 %.
+const MAX_DRIFT_CORRECTION: f64 = 0.005;
+
+/// Periodic Hann window over `FRAME`; at half overlap its halves add up
+/// to one.
+fn hann(i: usize) -> f32 {
+    (0.5 - 0.5 * (2.0 * ::std::f64::consts::PI * i as f64 / FRAME as f64).cos()) as f32
+}
+
+/// A broadcast delay on the programme, `delay.seconds` long.
+///
+/// `dump` drops the oldest `delay.dump` seconds (default the whole delay)
+/// so whatever was about to go on air never does. The delay is then built
+/// back up by playing `delay.build-rate` (default 0.05) slower than real
+/// time until it is full again: right away with `delay.rebuild = auto`, the
+/// default, or on the `build` command with `delay.rebuild = manual`. At
+/// start the delay builds up the same way.
+///
+/// Playing slower or faster stretches the programme in time without
+/// changing its pitch: it is cut into overlapping frames that are each
+/// taken from where they line up best with the previous one (WSOLA).
+/// Once built, small speed changes also keep the delay at its length when
+/// the sender's clock runs faster or slower than the sound card's.
+#[derive(Debug)]
+pub struct DelayLine {
+    target: usize,
+    dump: usize,
+    build_speed: f64,
+    auto_rebuild: bool,
+    samples: VecDeque<f32>,
+    /// Nominal read position in frames from the front of `samples`.
+    position: f64,
+    /// Where the programme taken for the last frame carries on, if it is
+    /// still in `samples`.
+    continuation: Option<usize>,
+    /// The windowed second half of the last frame, to add to the next.
+    tail: Vec<f32>,
+    /// Delay the drift correction holds in frames, smoothed.
+    measured: f64,
+    /// Delay the drift correction holds: the target, or after a dump
+    /// waiting for the `build` command whatever was left.
+    hold: usize,
+    building: bool,
+    dump_pending: bool,
+    dumps: usize,
+    start: Option<u64>,
+    frames: u64,
+}
+
+impl DelayLine {
+    pub fn from_config(config: &Config) -> Result<Option<Arc<Mutex<DelayLine>>>, Error> {
+        let seconds = match config.get::<f64>("delay.seconds")? {
+            Some(seconds) if seconds > 0.0 => seconds,
+            _ => return Ok(None),
+        };
+        let build_rate = config.get_or("delay.build-rate", 0.05f64)?;
+        if build_rate <= 0.0 || build_rate >= 1.0 {
+            return Err(Error::from(ConfigValueError("delay.build-rate".into(), build_rate.to_string())));
+        }
+        let auto_rebuild = match config.get_str("delay.rebuild") {
+            None | Some("auto") => true,
+            Some("manual") => false,
+            Some(other) => return Err(Error::from(ConfigValueError("delay.rebuild".into(), other.into()))),
+        };
+        let dump = config.get_or("delay.dump", seconds)?;
+
+        Ok(Some(Arc::new(Mutex::new(DelayLine::new(
+            (seconds * RATE as f64) as usize,
+            (dump * RATE as f64) as usize,
+            1.0 - build_rate,
+            auto_rebuild,
+        )))))
+    }
+
+    fn new(target: usize, dump: usize, build_speed: f64, auto_rebuild: bool) -> DelayLine {
+        DelayLine {
+            target,
+            dump,
+            build_speed,
+            auto_rebuild,
+            samples: VecDeque::new(),
+            position: 0.0,
+            continuation: None,
+            tail: vec![0.0; CHUNK * CHANNELS],
+            measured: 0.0,
+            hold: target,
+            building: true,
+            dump_pending: false,
+            dumps: 0,
+            start: None,
+            frames: 0,
+        }
+    }
+
+    /// Frames of programme waiting to go on air, i.e. the current delay.
+    fn delay_frames(&self) -> usize {
+        (self.samples.len() / CHANNELS).saturating_sub(self.position as usize)
+    }
+
+    /// Drops the first `frames` frames of programme, which have been
+    /// played or skipped.
+    fn discard(&mut self, frames: usize) {
+        let frames = frames.min(self.samples.len() / CHANNELS);
+        self.samples.drain(..frames * CHANNELS);
+        self.position = (self.position - frames as f64).max(0.0);
+        self.continuation = match self.continuation {
+            Some(continuation) if continuation >= frames => Some(continuation - frames),
+            _ => None,
+        };
+    }
+
+    fn push(&mut self, samples: &[f32]) {
+        self.samples.extend(samples);
+        // Skip ahead to at most a second more than the delay if the output
+        // stalls; the next frame crossfades over the jump.
+        let limit = self.target + RATE;
+        if self.delay_frames() > limit {
+            self.position += (self.delay_frames() - limit) as f64;
+            let skipped = (self.position as usize).saturating_sub(SEARCH);
+            self.discard(skipped);
+        }
+        if self.building && self.delay_frames() >= self.target {
+            self.building = false;
+            self.hold = self.target;
+            self.measured = self.delay_frames() as f64;
+            eprintln!("Delay built up to {:.1} s", self.target as f64 / RATE as f64);
+        }
+    }
+
+    pub fn dump(&mut self) -> String {
+        self.dump_pending = true;
+        self.dumps += 1;
+        format!("Dumping {:.1} s", self.dump.min(self.delay_frames()) as f64 / RATE as f64)
+    }
+
+    pub fn build(&mut self) -> String {
+        self.building = self.delay_frames() < self.target;
+        self.hold = self.target;
+        self.to_string()
+    }
+
+    /// Programme frames played per frame put out: slower while building,
+    /// and otherwise nudged to keep the delay at the length it should be.
+    fn speed(&self) -> f64 {
+        if self.building {
+            return self.build_speed;
+        }
+        let error = (self.measured - self.hold as f64) / RATE as f64;
+        if error.abs() < DRIFT_TOLERANCE {
+            return 1.0;
+        }
+        1.0 + (error * DRIFT_GAIN).max(-MAX_DRIFT_CORRECTION).min(MAX_DRIFT_CORRECTION)
+    }
+
+    /// The first channels of frames `start..start + len` summed and every
+    /// other frame skipped, enough to line frames up with.
+    fn mono(&self, start: usize, len: usize) -> Vec<f32> {
+        (start..start + len)
+            .step_by(2)
+            .map(|frame| (0..CHANNELS).map(|channel| self.samples[frame * CHANNELS + channel]).sum())
+            .collect()
+    }
+
+    /// Where to take the next frame from: the position within `SEARCH` of
+    /// `nominal` whose first half looks most like `continuation`, what
+    /// would have followed the previous frame, so that the two overlap
+    /// without cancelling out.
+    fn best_start(&self, nominal: usize, continuation: usize) -> usize {
+        let reference = self.mono(continuation, CHUNK);
+        let first = nominal.saturating_sub(SEARCH);
+        let candidates = self.mono(first, nominal + SEARCH - first + CHUNK);
+
+        let score = |offset: usize| {
+            let offset = offset / 2;
+            let (mut correlation, mut energy) = (0.0f32, 0.0f32);
+            for (a, b) in candidates[offset..offset + reference.len()].iter().zip(&reference) {
+                correlation += a * b;
+                energy += a * a;
+            }
+            if energy > 0.0 {
+                correlation / energy.sqrt()
+            } else {
+                0.0
+            }
+        };
+
+        let mut best = nominal;
+        let mut best_score = score(nominal - first);
+        for start in (first..nominal + SEARCH + 1).step_by(2) {
+            let start_score = score(start - first);
+            if start_score > best_score {
+                best = start;
+                best_score = start_score;
+            }
+        }
+        best
+    }
+
+    /// Plays the next chunk from the front of the line. Until enough
+    /// programme has come in it plays silence.
+    fn play(&mut self) -> Vec<f32> {
+        let speed = self.speed();
+
+        // A dump jumps ahead; the next frame crossfades over the jump.
+        if self.dump_pending {
+            self.dump_pending = false;
+            let dumped = self.dump.min(self.delay_frames().saturating_sub(FRAME + SEARCH));
+            self.position += dumped as f64;
+            if self.auto_rebuild {
+                self.building = true;
+            } else {
+                self.hold = self.delay_frames();
+                self.measured = self.hold as f64;
+            }
+        }
+
+        let nominal = self.position as usize;
+        if self.samples.len() / CHANNELS < nominal + SEARCH + FRAME {
+            // Out of programme: let the last frame fade out.
+            self.continuation = None;
+            return mem::replace(&mut self.tail, vec![0.0; CHUNK * CHANNELS]);
+        }
+
+        let start = match self.continuation {
+            Some(continuation) => self.best_start(nominal, continuation),
+            None => nominal,
+        };
+        let mut out = mem::replace(&mut self.tail, vec![0.0; CHUNK * CHANNELS]);
+        for i in 0..CHUNK {
+            let (rising, falling) = (hann(i), hann(CHUNK + i));
+            for channel in 0..CHANNELS {
+                let index = i * CHANNELS + channel;
+                out[index] += self.samples[(start + i) * CHANNELS + channel] * rising;
+                self.tail[index] = self.samples[(start + CHUNK + i) * CHANNELS + channel] * falling;
+            }
+        }
+        self.continuation = Some(start + CHUNK);
+        self.position += CHUNK as f64 * speed;
+
+        // Keep what the next search and the next frame's reference need.
+        let played = (self.position as usize).saturating_sub(SEARCH).min(start + CHUNK);
+        self.discard(played);
+        self.measured += (self.delay_frames() as f64 - self.measured) * 0.01;
+        out
+    }
+
+    fn push_output(&mut self, appsrc: &gst_app::AppSrc) {
+        if self.start.is_none() {
+            self.start = Some(running_time(appsrc.upcast_ref::<gst::Element>()));
+        }
+
+        let samples = self.play();
+        let mut buffer = match gst::Buffer::with_size(samples.len() * 4) {
+            Some(buffer) => buffer,
+            None => return,
+        };
+        {
+            let buffer = buffer.get_mut().unwrap();
+            if let Some(mut map) = buffer.map_writable() {
+                if let Ok(data) = map.as_mut_slice().as_mut_slice_of::<f32>() {
+                    data.copy_from_slice(&samples);
+                }
+            }
+            let pts = self.start.unwrap_or(0) + self.frames * 1_000_000_000 / RATE as u64;
+            buffer.set_pts(gst::ClockTime::from_nseconds(pts));
+            buffer.set_duration(gst::ClockTime::from_nseconds(CHUNK as u64 * 1_000_000_000 / RATE as u64));
+        }
+        self.frames += CHUNK as u64;
+        let _ = appsrc.push_buffer(buffer);
+    }
+}
+
+impl fmt::Display for DelayLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:.1} s of {:.1} s{}, {} dumps, speed {:.3}",
+            self.delay_frames() as f64 / RATE as f64,
+            self.target as f64 / RATE as f64,
+            if self.building { ", building" } else { "" },
+            self.dumps,
+            self.speed()
+        )
+    }
+}
+
+/// Makes the two ends of the delay: a sink taking the programme into the
+/// line and a live source playing it out.
+pub fn make_elements(line: &Arc<Mutex<DelayLine>>) -> Result<(gst::Element, gst::Element), Error> {
+    let sink = make_element("appsink", None)?;
+    sink.set_property("sync", &false.to_value())?;
+    let appsink = sink
+        .clone()
+        .dynamic_cast::<gst_app::AppSink>()
+        .expect("Sink element is expected to be an appsink!");
+    let input_line = line.clone();
+    appsink.set_callbacks(
+        gst_app::AppSinkCallbacks::new()
+            .new_sample(move |appsink| {
+                let sample = match appsink.pull_sample() {
+                    None => return gst::FlowReturn::Eos,
+                    Some(sample) => sample,
+                };
+                if let Some(buffer) = sample.get_buffer() {
+                    if let Some(map) = buffer.map_readable() {
+                        if let Ok(samples) = map.as_slice().as_slice_of::<f32>() {
+                            input_line.lock().unwrap().push(samples);
+                        }
+                    }
+                }
+                gst::FlowReturn::Ok
+            })
+            .build(),
+    );
+
+    let source = make_element("appsrc", None)?;
+    source.set_property("is-live", &true.to_value())?;
+    source.set_property_from_str("format", "time");
+    let appsrc = source
+        .clone()
+        .dynamic_cast::<gst_app::AppSrc>()
+        .expect("Source element is expected to be an appsrc!");
+    appsrc.set_caps(&gst::Caps::new_simple(
+        "audio/x-raw",
+        &[
+            ("format", &"F32LE"),
+            ("rate", &(RATE as i32)),
+            ("channels", &(CHANNELS as i32)),
+            ("layout", &"interleaved"),
+        ],
+    ));
+    let output_line = line.clone();
+    appsrc.set_callbacks(
+        gst_app::AppSrcCallbacks::new()
+            .need_data(move |appsrc, _| output_line.lock().unwrap().push_output(appsrc))
+            .build(),
+    );
+
+    Ok((make_bin(&[&make_input_chain()?, &sink])?, source))
+}
+
+/// Adds the `delay dump`, `delay build` and `delay status` commands.
+pub fn register_commands(control: &mut Control, line: &Arc<Mutex<DelayLine>>) {
+    let line = line.clone();
+    control.register("delay", move |args| {
+        let mut line = line.lock().unwrap();
+        match args.first() {
+            Some(&"dump") => Ok(line.dump()),
+            Some(&"build") => Ok(line.build()),
+            Some(&"status") | None => Ok(line.to_string()),
+            Some(other) => Err(Error::from(UnknownCommand(format!("delay {}", other)))),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TARGET: usize = RATE / 2;
+
+    /// A 440 Hz tone, continuing from `phase`.
+    fn tone(frames: usize, phase: &mut f64) -> Vec<f32> {
+        let mut samples = Vec::with_capacity(frames * CHANNELS);
+        for _ in 0..frames {
+            let x = (0.5 * phase.sin()) as f32;
+            samples.extend_from_slice(&[x, x]);
+            *phase += 2.0 * ::std::f64::consts::PI * 440.0 / RATE as f64;
+        }
+        samples
+    }
+
+    /// Pushes and plays `chunks` chunks, returning the first channel of
+    /// what was played.
+    fn run(line: &mut DelayLine, chunks: usize, phase: &mut f64) -> Vec<f32> {
+        let mut played = Vec::with_capacity(chunks * CHUNK);
+        for _ in 0..chunks {
+            line.push(&tone(CHUNK, phase));
+            played.extend(line.play().iter().step_by(CHANNELS));
+        }
+        played
+    }
+
+    fn seconds(frames: usize) -> f64 {
+        frames as f64 / RATE as f64
+    }
+
+    #[test]
+    fn builds_up_at_the_build_speed() {
+        let mut line = DelayLine::new(TARGET, TARGET, 0.95, true);
+        let mut phase = 0.0;
+        // Half a second of delay at 5 % takes ten seconds, less the first
+        // frame waited for before playing.
+        run(&mut line, 900, &mut phase);
+        assert!(line.building, "built after {:.2} s", seconds(line.delay_frames()));
+        run(&mut line, 100, &mut phase);
+        assert!(!line.building);
+        assert!((seconds(line.delay_frames()) - 0.5).abs() < 0.02, "{}", line);
+    }
+
+    #[test]
+    fn stretching_keeps_the_pitch() {
+        let mut line = DelayLine::new(TARGET, TARGET, 0.95, true);
+        let mut phase = 0.0;
+        let played = run(&mut line, 500, &mut phase);
+        let second = &played[2 * RATE..3 * RATE];
+        let crossings = second.windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0).count();
+        assert!((439..=441).contains(&crossings), "{} Hz", crossings);
+        let largest_step = second.windows(2).map(|w| (w[1] - w[0]).abs()).fold(0.0, f32::max);
+        assert!(largest_step < 0.03, "step of {}", largest_step);
+    }
+
+    #[test]
+    fn dump_and_automatic_rebuild() {
+        let mut line = DelayLine::new(TARGET, TARGET / 2, 0.95, true);
+        let mut phase = 0.0;
+        run(&mut line, 1100, &mut phase);
+        line.dump();
+        run(&mut line, 1, &mut phase);
+        assert!(line.building);
+        assert!((seconds(line.delay_frames()) - 0.25).abs() < 0.02, "{}", line);
+
+        run(&mut line, 550, &mut phase);
+        assert!(!line.building);
+        assert!((seconds(line.delay_frames()) - 0.5).abs() < 0.02, "{}", line);
+    }
+
+    #[test]
+    fn dump_and_manual_rebuild() {
+        let mut line = DelayLine::new(TARGET, TARGET, 0.95, false);
+        let mut phase = 0.0;
+        run(&mut line, 1100, &mut phase);
+        line.dump();
+        run(&mut line, 300, &mut phase);
+        assert!(!line.building);
+        assert!(seconds(line.delay_frames()) < 0.02, "{}", line);
+
+        line.build();
+        assert!(line.building);
+        run(&mut line, 1100, &mut phase);
+        assert!(!line.building);
+        assert!((seconds(line.delay_frames()) - 0.5).abs() < 0.02, "{}", line);
+    }
+
+    #[test]
+    fn follows_a_fast_sender() {
+        let mut line = DelayLine::new(TARGET, TARGET, 0.95, true);
+        let mut phase = 0.0;
+        run(&mut line, 1100, &mut phase);
+        // The sender's clock runs 0.2 % fast.
+        for chunk in 0..6000 {
+            let frames = if chunk % 5 == 0 { CHUNK + 5 } else { CHUNK };
+            line.push(&tone(frames, &mut phase));
+            line.play();
+        }
+        assert!((seconds(line.delay_frames()) - 0.5).abs() < 0.05, "{}", line);
+    }
+}
@@ -0,0 +1,286 @@
+use gst;
+use gst::prelude::*;
+use gst_app;
+
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use failure::Error;
+
+use common::{get_static_pad, make_bin, make_element, running_time};
+use config::{Config, ConfigValueError};
+use selector::make_input_chain;
+
+#[derive(Debug, Fail)]
+#[fail(display = "Cannot play fallback file {}", _0)]
+struct UnplayableFile(String);
+
+const RATE: u64 = 48000;
+const CHANNELS: usize = 2;
+
+/// Files to loop from `fallback.file`, a comma separated list, or from the
+/// `fallback.playlist` file with one path or URI per line.
+fn playlist(config: &Config) -> Result<Vec<String>, Error> {
+    let mut files = config
+        .get_str("fallback.file")
+        .map(|files| {
+            files
+                .split(',')
+                .map(|file| file.trim().to_string())
+                .filter(|file| !file.is_empty())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_else(Vec::new);
+
+    if let Some(path) = config.get_str("fallback.playlist") {
+        for line in BufReader::new(fs::File::open(path)?).lines() {
+            let line = line?;
+            let line = line.trim();
+            if !line.is_empty() && !line.starts_with('#') {
+                files.push(line.to_string());
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+fn to_uri(file: &str) -> Result<String, Error> {
+    if file.contains("://") {
+        Ok(file.to_string())
+    } else {
+        Ok(format!("file://{}", fs::canonicalize(Path::new(file))?.display()))
+    }
+}
+
+/// Decodes one file of the playlist into an appsink the player pulls from.
+fn open_file(file: &str) -> Result<(gst::Pipeline, gst_app::AppSink), Error> {
+    let pipeline = gst::Pipeline::new(None);
+    let decodebin = make_element("uridecodebin", None)?;
+    let chain = make_input_chain()?;
+    let sink = make_element("appsink", None)?;
+
+    pipeline.add_many(&[&decodebin, &chain, &sink])?;
+    chain.link(&sink)?;
+    decodebin.set_property("uri", &to_uri(file)?.to_value())?;
+    sink.set_property("sync", &false.to_value())?;
+    sink.set_property("max-buffers", &4u32.to_value())?;
+
+    let chain_sinkpad = get_static_pad(&chain, "sink")?;
+    decodebin.connect_pad_added(move |_, src_pad| {
+        let is_audio = src_pad
+            .get_current_caps()
+            .and_then(|caps| caps.get_structure(0).map(|s| s.get_name().starts_with("audio/")))
+            .unwrap_or(false);
+        if is_audio && !chain_sinkpad.is_linked() {
+            let _ = src_pad.link(&chain_sinkpad);
+        }
+    });
+
+    let appsink = sink
+        .dynamic_cast::<gst_app::AppSink>()
+        .expect("Sink element is expected to be an appsink!");
+    // Wait for the file to preroll so a broken one fails here instead of
+    // leaving the player waiting for samples forever.
+    pipeline.set_state(gst::State::Playing);
+    let (ret, _, _) = pipeline.get_state(gst::ClockTime::from_seconds(5));
+    if ret == gst::StateChangeReturn::Failure || ret == gst::StateChangeReturn::Async {
+        pipeline.set_state(gst::State::Null);
+        return Err(Error::from(UnplayableFile(file.to_string())));
+    }
+
+    Ok((pipeline, appsink))
+}
+
+type OpenFile = (gst::Pipeline, gst_app::AppSink);
+
+/// How long to wait before trying the playlist again once every file in it
+/// has failed to open.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Plays the playlist in a loop, timestamped in the running time of the
+/// receiver pipeline so the mixer can mix it with the live link. Files are
+/// opened on a thread of their own, as prerolling one can take seconds,
+/// and the player pushes silence until the file is ready.
+struct FilePlayer {
+    files: Vec<String>,
+    next: usize,
+    current: Option<OpenFile>,
+    opening: Option<(String, Receiver<Result<OpenFile, Error>>)>,
+    failures: usize,
+    retry_at: Option<Instant>,
+    start: Option<u64>,
+    frames: u64,
+}
+
+impl FilePlayer {
+    fn new(files: Vec<String>) -> FilePlayer {
+        FilePlayer {
+            files,
+            next: 0,
+            current: None,
+            opening: None,
+            failures: 0,
+            retry_at: None,
+            start: None,
+            frames: 0,
+        }
+    }
+
+    fn start_opening(&mut self) {
+        if let Some(retry_at) = self.retry_at {
+            if Instant::now() < retry_at {
+                return;
+            }
+            self.retry_at = None;
+        }
+
+        let file = self.files[self.next].clone();
+        self.next = (self.next + 1) % self.files.len();
+        let (sender, receiver) = mpsc::channel();
+        let thread_file = file.clone();
+        thread::spawn(move || {
+            let _ = sender.send(open_file(&thread_file));
+        });
+        self.opening = Some((file, receiver));
+    }
+
+    /// Takes the file being opened if it is ready.
+    fn poll_opening(&mut self) {
+        let (file, result) = match self.opening.take() {
+            Some((file, receiver)) => match receiver.try_recv() {
+                Ok(result) => (file, result),
+                Err(TryRecvError::Empty) => {
+                    self.opening = Some((file, receiver));
+                    return;
+                }
+                Err(TryRecvError::Disconnected) => {
+                    let err = Error::from(UnplayableFile(file.clone()));
+                    (file, Err(err))
+                }
+            },
+            None => return,
+        };
+
+        match result {
+            Ok(current) => {
+                self.current = Some(current);
+                self.failures = 0;
+            }
+            Err(err) => {
+                eprintln!("Failed to play fallback {}: {}", file, err);
+                self.failures += 1;
+                if self.failures >= self.files.len() {
+                    self.failures = 0;
+                    self.retry_at = Some(Instant::now() + RETRY_DELAY);
+                }
+            }
+        }
+    }
+
+    /// The next sample of the playlist, or `None` while a file is opening.
+    fn next_sample(&mut self) -> Option<gst::Sample> {
+        self.poll_opening();
+        if self.current.is_none() {
+            if self.opening.is_none() {
+                self.start_opening();
+            }
+            return None;
+        }
+
+        let sample = self.current.as_ref().and_then(|&(_, ref appsink)| appsink.pull_sample());
+        if sample.is_none() {
+            if let Some((pipeline, _)) = self.current.take() {
+                pipeline.set_state(gst::State::Null);
+            }
+            self.start_opening();
+        }
+        sample
+    }
+
+    fn push(&mut self, appsrc: &gst_app::AppSrc) {
+        if self.start.is_none() {
+            self.start = Some(running_time(appsrc.upcast_ref::<gst::Element>()));
+        }
+
+        let data = match self.next_sample().and_then(|sample| sample.get_buffer()) {
+            Some(buffer) => match buffer.map_readable() {
+                Some(map) => map.as_slice().to_vec(),
+                None => return,
+            },
+            None => vec![0u8; RATE as usize / 100 * CHANNELS * 4],
+        };
+
+        let frames = (data.len() / (CHANNELS * 4)) as u64;
+        let mut buffer = match gst::Buffer::from_mut_slice(data) {
+            Some(buffer) => buffer,
+            None => return,
+        };
+        {
+            let buffer = buffer.get_mut().unwrap();
+            let pts = self.start.unwrap_or(0) + self.frames * 1_000_000_000 / RATE;
+            buffer.set_pts(gst::ClockTime::from_nseconds(pts));
+            buffer.set_duration(gst::ClockTime::from_nseconds(frames * 1_000_000_000 / RATE));
+        }
+        self.frames += frames;
+        let _ = appsrc.push_buffer(buffer);
+    }
+}
+
+fn make_file_source(files: Vec<String>) -> Result<gst::Element, Error> {
+    let element = make_element("appsrc", None)?;
+    element.set_property("is-live", &true.to_value())?;
+    element.set_property_from_str("format", "time");
+    let appsrc = element
+        .clone()
+        .dynamic_cast::<gst_app::AppSrc>()
+        .expect("Source element is expected to be an appsrc!");
+    appsrc.set_caps(&gst::Caps::new_simple(
+        "audio/x-raw",
+        &[
+            ("format", &"F32LE"),
+            ("rate", &(RATE as i32)),
+            ("channels", &(CHANNELS as i32)),
+            ("layout", &"interleaved"),
+        ],
+    ));
+
+    let player = Mutex::new(FilePlayer::new(files));
+    appsrc.set_callbacks(
+        gst_app::AppSrcCallbacks::new()
+            .need_data(move |appsrc, _| player.lock().unwrap().push(appsrc))
+            .build(),
+    );
+
+    Ok(element)
+}
+
+/// Makes the source the receiver falls back to when the link fails, from
+/// `fallback.source`: `file` loops the files of `fallback.file` or
+/// `fallback.playlist`, `jack` takes a separate JACK input. Returns `None`
+/// if no fallback is configured.
+pub fn make_source(config: &Config) -> Result<Option<gst::Element>, Error> {
+    let source = match config.get_str("fallback.source") {
+        None => return Ok(None),
+        Some("file") => {
+            let files = playlist(config)?;
+            if files.is_empty() {
+                return Err(Error::from(ConfigValueError("fallback.file".into(), "missing".into())));
+            }
+            make_file_source(files)?
+        }
+        Some("jack") => {
+            let jackaudiosrc = make_element("jackaudiosrc", None)?;
+            jackaudiosrc.set_property("client-name", &"tlink-fallback".to_value())?;
+            jackaudiosrc
+        }
+        Some(other) => return Err(Error::from(ConfigValueError("fallback.source".into(), other.into()))),
+    };
+
+    Ok(Some(make_bin(&[&source, &make_input_chain()?])?))
+}
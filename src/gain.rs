@@ -0,0 +1,463 @@
+use gst;
+use gst::prelude::*;
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::{Arc, Mutex, Weak};
+
+use failure::Error;
+
+use audio;
+use common::{get_static_pad, make_bin, make_element};
+use config::{Config, ConfigValueError};
+use control::{parse_arg, Control, InvalidArgument, UnknownCommand};
+
+/// Most gain the `volume` element allows, 10x.
+const MAX_GAIN_DB: f64 = 20.0;
+
+/// Width of the compressor's soft knee around the threshold.
+const KNEE_DB: f64 = 6.0;
+
+fn db_to_linear(db: f64) -> f64 {
+    10f64.powf(db / 20.0)
+}
+
+/// One-pole smoothing coefficient reaching 63 % in `ms` milliseconds.
+fn coefficient(ms: f64, rate: u32) -> f64 {
+    1.0 - (-1000.0 / (ms.max(0.01) * rate as f64)).exp()
+}
+
+/// Dynamics processing from `dynamics`: `compressor` or `limiter`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DynamicsMode {
+    Compressor,
+    Limiter,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Settings {
+    gain_db: f64,
+    mute: bool,
+    dynamics: Option<(DynamicsMode, f64, f64)>,
+}
+
+/// State of the dynamics processing carried from buffer to buffer.
+#[derive(Debug, Default)]
+struct Envelope {
+    rate: u32,
+    channels: usize,
+    /// Compressor gain reduction in dB.
+    reduction_db: f64,
+    /// Limiter look-ahead in frames.
+    lookahead: usize,
+    /// The input delayed by the look-ahead.
+    delayed: VecDeque<f32>,
+    /// Gains the frames within the look-ahead need, as `(frame, gain)`
+    /// with the gains rising from the front so it holds the smallest.
+    needed: VecDeque<(u64, f32)>,
+    /// The smallest needed gains of the last look-ahead, averaged into a
+    /// smooth attack that reaches each one by the time its frame is out.
+    held: VecDeque<f32>,
+    held_sum: f64,
+    gain: f32,
+    frame: u64,
+}
+
+impl Envelope {
+    /// Starts over at the rate and channels of `block`.
+    fn reset(&mut self, block: &audio::AudioBlock, attack_ms: f64) {
+        let lookahead = ((attack_ms * block.rate as f64 / 1000.0) as usize).max(1);
+        *self = Envelope {
+            rate: block.rate,
+            channels: block.channels,
+            lookahead,
+            delayed: vec![0.0; lookahead * block.channels].into(),
+            held: vec![1.0; lookahead + 1].into(),
+            held_sum: (lookahead + 1) as f64,
+            gain: 1.0,
+            ..Envelope::default()
+        };
+    }
+
+    /// Compresses `block` in place, a soft knee around the threshold and
+    /// the gain reduction following the peaks with the attack and release
+    /// times.
+    fn compress(&mut self, block: &mut audio::AudioBlock, threshold_db: f64, ratio: f64, attack: f64, release: f64) {
+        let slope = 1.0 - 1.0 / ratio;
+        for frame in block.samples.chunks_mut(block.channels) {
+            let peak = frame.iter().fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+            let over = 20.0 * (peak.max(1e-5) as f64).log10() - threshold_db;
+            let target = if 2.0 * over < -KNEE_DB {
+                0.0
+            } else if 2.0 * over > KNEE_DB {
+                slope * over
+            } else {
+                slope * (over + KNEE_DB / 2.0).powi(2) / (2.0 * KNEE_DB)
+            };
+            let coefficient = if target > self.reduction_db { attack } else { release };
+            self.reduction_db += (target - self.reduction_db) * coefficient;
+
+            let gain = db_to_linear(-self.reduction_db) as f32;
+            for sample in frame {
+                *sample *= gain;
+            }
+        }
+    }
+
+    /// Limits `block` in place to the threshold. The output lags the input
+    /// by the look-ahead, so the gain can come down ahead of a peak instead
+    /// of clipping its start, and goes back up with the release time.
+    fn limit(&mut self, block: &mut audio::AudioBlock, threshold_db: f64, release: f64) {
+        let threshold = db_to_linear(threshold_db) as f32;
+        for frame in block.samples.chunks_mut(block.channels) {
+            let peak = frame.iter().fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+            let needed = if peak > threshold { threshold / peak } else { 1.0 };
+            while self.needed.back().map(|&(_, gain)| gain >= needed).unwrap_or(false) {
+                self.needed.pop_back();
+            }
+            self.needed.push_back((self.frame, needed));
+            let oldest = self.frame.saturating_sub(self.lookahead as u64);
+            while self.needed.front().map(|&(frame, _)| frame < oldest).unwrap_or(false) {
+                self.needed.pop_front();
+            }
+            let smallest = self.needed.front().map(|&(_, gain)| gain).unwrap_or(1.0);
+            self.frame += 1;
+
+            self.held.push_back(smallest);
+            self.held_sum += smallest as f64;
+            self.held_sum -= self.held.pop_front().unwrap_or(0.0) as f64;
+            let attack = (self.held_sum / self.held.len() as f64) as f32;
+            self.gain = if attack < self.gain {
+                attack
+            } else {
+                self.gain + (attack - self.gain) * release as f32
+            };
+
+            for sample in frame {
+                self.delayed.push_back(*sample);
+                *sample = self.delayed.pop_front().unwrap_or(0.0) * self.gain;
+            }
+        }
+    }
+}
+
+/// Gain and dynamics of the programme before it is encoded.
+///
+/// The input is amplified by `gain.db` (default 0, at most +20) and muted
+/// if `gain.mute` is true. `dynamics = compressor` compresses the audio
+/// above `dynamics.threshold` dBFS (default -12) by `dynamics.ratio`
+/// (default 4); `dynamics = limiter` holds the peaks at the threshold
+/// (default -1 dBFS) so a hot field mic does not clip the codec. The gain
+/// comes down in `dynamics.attack` ms (default 5) and goes back up in
+/// `dynamics.release` ms (default 100). The limiter looks ahead by the
+/// attack time, delaying the programme by as much. All of it can be
+/// changed at runtime with the `gain`, `mute`, `unmute` and `dynamics`
+/// commands, apart from the attack and release.
+#[derive(Debug)]
+pub struct InputStage {
+    volume: gst::Element,
+    envelope: Option<Mutex<Envelope>>,
+    attack_ms: f64,
+    release_ms: f64,
+    settings: Mutex<Settings>,
+}
+
+impl InputStage {
+    pub fn from_config(config: &Config) -> Result<Arc<InputStage>, Error> {
+        let mode = match config.get_str("dynamics") {
+            None | Some("off") => None,
+            Some("compressor") => Some(DynamicsMode::Compressor),
+            Some("limiter") => Some(DynamicsMode::Limiter),
+            Some(other) => return Err(Error::from(ConfigValueError("dynamics".into(), other.into()))),
+        };
+        let dynamics = match mode {
+            Some(DynamicsMode::Compressor) => Some((
+                DynamicsMode::Compressor,
+                config.get_or("dynamics.threshold", -12.0f64)?,
+                config.get_or("dynamics.ratio", 4.0f64)?,
+            )),
+            Some(DynamicsMode::Limiter) => Some((
+                DynamicsMode::Limiter,
+                config.get_or("dynamics.threshold", -1.0f64)?,
+                ::std::f64::INFINITY,
+            )),
+            None => None,
+        };
+
+        let attack_ms = config.get_or("dynamics.attack", 5.0f64)?;
+        if attack_ms.is_nan() || attack_ms <= 0.0 {
+            return Err(Error::from(ConfigValueError("dynamics.attack".into(), attack_ms.to_string())));
+        }
+        let release_ms = config.get_or("dynamics.release", 100.0f64)?;
+        if release_ms.is_nan() || release_ms <= 0.0 {
+            return Err(Error::from(ConfigValueError("dynamics.release".into(), release_ms.to_string())));
+        }
+
+        let stage = Arc::new(InputStage {
+            volume: make_element("volume", "inputgain")?,
+            envelope: match dynamics {
+                Some(_) => Some(Mutex::new(Envelope::default())),
+                None => None,
+            },
+            attack_ms,
+            release_ms,
+            settings: Mutex::new(Settings {
+                gain_db: 0.0,
+                mute: false,
+                dynamics,
+            }),
+        });
+        stage.set_gain_db(config.get_or("gain.db", 0.0f64)?)?;
+        stage.set_mute(config.get_or("gain.mute", false)?)?;
+        if let Some((_, threshold_db, ratio)) = dynamics {
+            stage.set_dynamics(threshold_db, ratio)?;
+
+            // The volume element owns the probe, so it must not own the stage.
+            let probe_stage = Arc::downgrade(&stage);
+            get_static_pad(&stage.volume, "src")?.add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+                let probe_stage = match Weak::upgrade(&probe_stage) {
+                    Some(stage) => stage,
+                    None => return gst::PadProbeReturn::Remove,
+                };
+                if let Some(gst::PadProbeData::Buffer(ref mut buffer)) = info.data {
+                    let buffer = buffer.make_mut();
+                    if let Some(mut block) = audio::read_block(pad, buffer) {
+                        probe_stage.process(&mut block);
+                        audio::write_block(pad, buffer, &block.samples);
+                    }
+                }
+                gst::PadProbeReturn::Ok
+            });
+        }
+        Ok(stage)
+    }
+
+    /// Makes the elements to put between `audioconvert` and the encoder.
+    /// With dynamics the volume's output is held to the formats
+    /// `audio::read_block` understands, so they cannot silently stop.
+    pub fn make_elements(&self) -> Result<gst::Element, Error> {
+        if self.envelope.is_none() {
+            return make_bin(&[&self.volume]);
+        }
+        let capsfilter = make_element("capsfilter", None)?;
+        let caps = gst::Caps::from_string("audio/x-raw, format = (string) { F32LE, S16LE }").expect("Invalid caps");
+        capsfilter.set_property("caps", &caps.to_value())?;
+        make_bin(&[&self.volume, &capsfilter])
+    }
+
+    /// Runs the dynamics processing on a block coming out of the volume.
+    fn process(&self, block: &mut audio::AudioBlock) {
+        let envelope = match self.envelope {
+            Some(ref envelope) => envelope,
+            None => return,
+        };
+        let (mode, threshold_db, ratio) = match self.settings.lock().unwrap().dynamics {
+            Some(dynamics) => dynamics,
+            None => return,
+        };
+
+        let mut envelope = envelope.lock().unwrap();
+        if envelope.rate != block.rate || envelope.channels != block.channels {
+            envelope.reset(block, self.attack_ms);
+        }
+        let release = coefficient(self.release_ms, block.rate);
+        match mode {
+            DynamicsMode::Compressor => {
+                let attack = coefficient(self.attack_ms, block.rate);
+                envelope.compress(block, threshold_db, ratio, attack, release)
+            }
+            DynamicsMode::Limiter => envelope.limit(block, threshold_db, release),
+        }
+    }
+
+    pub fn set_gain_db(&self, gain_db: f64) -> Result<(), Error> {
+        if gain_db.is_nan() || gain_db > MAX_GAIN_DB {
+            return Err(Error::from(InvalidArgument(gain_db.to_string())));
+        }
+        self.volume.set_property("volume", &db_to_linear(gain_db).to_value())?;
+        self.settings.lock().unwrap().gain_db = gain_db;
+        Ok(())
+    }
+
+    pub fn set_mute(&self, mute: bool) -> Result<(), Error> {
+        self.volume.set_property("mute", &mute.to_value())?;
+        self.settings.lock().unwrap().mute = mute;
+        Ok(())
+    }
+
+    /// Sets the threshold in dBFS and the compression ratio, which the
+    /// limiter ignores.
+    pub fn set_dynamics(&self, threshold_db: f64, ratio: f64) -> Result<(), Error> {
+        if threshold_db > 0.0 {
+            return Err(Error::from(InvalidArgument(threshold_db.to_string())));
+        }
+        if ratio.is_nan() || ratio < 1.0 {
+            return Err(Error::from(InvalidArgument(ratio.to_string())));
+        }
+
+        let mut settings = self.settings.lock().unwrap();
+        let mode = match settings.dynamics {
+            Some((mode, _, _)) => mode,
+            None => return Err(Error::from(UnknownCommand("dynamics".into()))),
+        };
+        settings.dynamics = Some((mode, threshold_db, ratio));
+        Ok(())
+    }
+}
+
+impl fmt::Display for InputStage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let settings = self.settings.lock().unwrap();
+        write!(f, "gain {:+.1} dB", settings.gain_db)?;
+        if settings.mute {
+            write!(f, ", MUTED")?;
+        }
+        match settings.dynamics {
+            Some((DynamicsMode::Compressor, threshold_db, ratio)) => {
+                write!(f, ", compressor {:.1} dBFS {}:1", threshold_db, ratio)
+            }
+            Some((DynamicsMode::Limiter, threshold_db, _)) => write!(f, ", limiter {:.1} dBFS", threshold_db),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Adds `gain [DB]`, `mute`, `unmute` and, with dynamics configured,
+/// `dynamics threshold DB` and `dynamics ratio RATIO`.
+pub fn register_commands(control: &mut Control, stage: &Arc<InputStage>) {
+    let gain_stage = stage.clone();
+    control.register("gain", move |args| {
+        if !args.is_empty() {
+            gain_stage.set_gain_db(parse_arg(args)?)?;
+        }
+        Ok(gain_stage.to_string())
+    });
+
+    let mute_stage = stage.clone();
+    control.register("mute", move |_| {
+        mute_stage.set_mute(true)?;
+        Ok(mute_stage.to_string())
+    });
+
+    let unmute_stage = stage.clone();
+    control.register("unmute", move |_| {
+        unmute_stage.set_mute(false)?;
+        Ok(unmute_stage.to_string())
+    });
+
+    if stage.envelope.is_none() {
+        return;
+    }
+    let dynamics_stage = stage.clone();
+    control.register("dynamics", move |args| {
+        let current = dynamics_stage.settings.lock().unwrap().dynamics;
+        let (_, threshold_db, ratio) = match current {
+            Some(dynamics) => dynamics,
+            None => return Err(Error::from(UnknownCommand("dynamics".into()))),
+        };
+        match args.split_first() {
+            Some((&"threshold", value)) => dynamics_stage.set_dynamics(parse_arg(value)?, ratio)?,
+            Some((&"ratio", value)) => dynamics_stage.set_dynamics(threshold_db, parse_arg(value)?)?,
+            Some((other, _)) => return Err(Error::from(UnknownCommand(format!("dynamics {}", other)))),
+            None => (),
+        }
+        Ok(dynamics_stage.to_string())
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+
+    /// A stereo square wave of `amplitude` for `ms` milliseconds. Its peak
+    /// level is the same in every frame.
+    fn square(amplitude: f32, ms: usize) -> audio::AudioBlock {
+        let frames = RATE as usize / 1000 * ms;
+        let samples = (0..frames)
+            .flat_map(|i| {
+                let x = if i / 24 % 2 == 0 { amplitude } else { -amplitude };
+                vec![x, x]
+            })
+            .collect();
+        audio::AudioBlock {
+            rate: RATE,
+            channels: 2,
+            samples,
+        }
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0.0, |peak, sample| peak.max(sample.abs()))
+    }
+
+    fn compress(envelope: &mut Envelope, block: &mut audio::AudioBlock) {
+        if envelope.rate == 0 {
+            envelope.reset(block, 5.0);
+        }
+        let (attack, release) = (coefficient(5.0, RATE), coefficient(100.0, RATE));
+        envelope.compress(block, -20.0, 4.0, attack, release);
+    }
+
+    fn limit(envelope: &mut Envelope, block: &mut audio::AudioBlock) {
+        if envelope.rate == 0 {
+            envelope.reset(block, 5.0);
+        }
+        envelope.limit(block, -6.0, coefficient(100.0, RATE));
+    }
+
+    #[test]
+    fn limiter_holds_peaks_at_the_threshold() {
+        let mut envelope = Envelope::default();
+        let threshold = db_to_linear(-6.0) as f32;
+        let mut output = Vec::new();
+        // Quiet, then a sudden full scale peak, then quiet again.
+        for &(amplitude, ms) in &[(0.1, 100), (1.0, 50), (0.1, 100), (2.0, 1), (0.1, 100)] {
+            let mut block = square(amplitude, ms);
+            limit(&mut envelope, &mut block);
+            output.extend(block.samples);
+        }
+
+        assert!(peak(&output) <= threshold * 1.0001, "peak {}", peak(&output));
+        // The look-ahead delays the programme by 5 ms.
+        let lookahead = RATE as usize / 200 * 2;
+        assert_eq!(peak(&output[..lookahead]), 0.0);
+        assert!((peak(&output[lookahead..RATE as usize / 10 * 2]) - 0.1).abs() < 1e-6);
+    }
+
+    #[test]
+    fn compressor_reduces_by_the_ratio() {
+        let mut envelope = Envelope::default();
+        let mut block = square(0.5, 1000);
+        compress(&mut envelope, &mut block);
+
+        // 14 dB over the threshold comes out 3.5 dB over it.
+        let over_db = 20.0 * 0.5f64.log10() + 20.0;
+        let output_db = 20.0 * f64::from(peak(&block.samples[block.samples.len() / 2..])).log10();
+        assert!((output_db - (-20.0 + over_db / 4.0)).abs() < 0.05, "{} dBFS", output_db);
+    }
+
+    #[test]
+    fn compressor_leaves_quiet_audio_alone() {
+        let mut envelope = Envelope::default();
+        let mut block = square(0.01, 1000);
+        compress(&mut envelope, &mut block);
+        assert!((peak(&block.samples) - 0.01).abs() < 1e-6);
+    }
+
+    #[test]
+    fn release_returns_to_unity() {
+        let mut compressor = Envelope::default();
+        let mut limiter = Envelope::default();
+        for &amplitude in &[1.0, 0.01] {
+            let mut block = square(amplitude, 1000);
+            compress(&mut compressor, &mut block);
+            let mut block = square(amplitude, 1000);
+            limit(&mut limiter, &mut block);
+        }
+
+        assert!(compressor.reduction_db < 0.01, "{} dB", compressor.reduction_db);
+        assert!(limiter.gain > 0.999, "gain {}", limiter.gain);
+    }
+}
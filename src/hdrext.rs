@@ -0,0 +1,378 @@
+use gst;
+use gst::prelude::*;
+
+use std::fs::File;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use failure::Error;
+
+use config::{Config, ConfigValueError};
+
+/// Seconds from the NTP epoch (1900) to the Unix epoch.
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// Profile of the RFC 8285 one-byte header extension.
+const ONE_BYTE_PROFILE: u16 = 0xBEDE;
+
+/// IDs of the send time header extensions, from `hdrext.ntp-64` and
+/// `hdrext.abs-send-time`. An ID of 0 leaves that extension out.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ExtensionIds {
+    pub ntp64: u8,
+    pub abs_send_time: u8,
+}
+
+impl ExtensionIds {
+    /// Returns `None` when neither extension is configured.
+    pub fn from_config(config: &Config) -> Result<Option<ExtensionIds>, Error> {
+        let ids = ExtensionIds {
+            ntp64: extension_id(config, "hdrext.ntp-64")?,
+            abs_send_time: extension_id(config, "hdrext.abs-send-time")?,
+        };
+
+        if ids.ntp64 == 0 && ids.abs_send_time == 0 {
+            Ok(None)
+        } else {
+            Ok(Some(ids))
+        }
+    }
+}
+
+fn extension_id(config: &Config, key: &str) -> Result<u8, Error> {
+    let id = config.get_or(key, 0u8)?;
+    if id > 14 {
+        return Err(Error::from(ConfigValueError(key.into(), id.to_string())));
+    }
+    Ok(id)
+}
+
+/// The wall clock as a 64 bit NTP timestamp, 32.32 fixed point seconds.
+pub fn ntp_now() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System clock before 1970");
+    let fraction = ((now.subsec_nanos() as u64) << 32) / 1_000_000_000;
+    ((now.as_secs() + NTP_UNIX_OFFSET) << 32) | fraction
+}
+
+/// abs-send-time is 6.18 fixed point seconds, wrapping every 64 s.
+fn abs_send_time(ntp: u64) -> u32 {
+    ((ntp >> 14) & 0x00FF_FFFF) as u32
+}
+
+fn header_len(packet: &[u8]) -> Option<usize> {
+    if packet.len() < 12 || packet[0] >> 6 != 2 {
+        return None;
+    }
+    let len = 12 + 4 * (packet[0] & 0x0F) as usize;
+    if packet.len() < len {
+        None
+    } else {
+        Some(len)
+    }
+}
+
+/// Adds the send time extensions to an RTP packet. Returns `None` if the
+/// packet is not RTP or already carries a header extension of another
+/// profile; one-byte extensions already present are kept.
+pub fn add_send_time(packet: &[u8], ids: ExtensionIds, ntp: u64) -> Option<Vec<u8>> {
+    let header_len = header_len(packet)?;
+    let has_extension = packet[0] & 0x10 != 0;
+
+    let (mut elements, payload_start) = if has_extension {
+        if packet.len() < header_len + 4 {
+            return None;
+        }
+        let profile = (packet[header_len] as u16) << 8 | packet[header_len + 1] as u16;
+        let words = (packet[header_len + 2] as usize) << 8 | packet[header_len + 3] as usize;
+        let end = header_len + 4 + 4 * words;
+        if profile != ONE_BYTE_PROFILE || packet.len() < end {
+            return None;
+        }
+        let mut elements = packet[header_len + 4..end].to_vec();
+        while elements.last() == Some(&0) {
+            elements.pop();
+        }
+        (elements, end)
+    } else {
+        (Vec::new(), header_len)
+    };
+
+    if ids.ntp64 != 0 {
+        elements.push(ids.ntp64 << 4 | 7);
+        for i in (0..8).rev() {
+            elements.push((ntp >> (8 * i)) as u8);
+        }
+    }
+    if ids.abs_send_time != 0 {
+        let ast = abs_send_time(ntp);
+        elements.push(ids.abs_send_time << 4 | 2);
+        elements.extend_from_slice(&[(ast >> 16) as u8, (ast >> 8) as u8, ast as u8]);
+    }
+    while elements.len() % 4 != 0 {
+        elements.push(0);
+    }
+
+    let words = elements.len() / 4;
+    let mut out = Vec::with_capacity(packet.len() + elements.len() + 4);
+    out.extend_from_slice(&packet[..header_len]);
+    out[0] |= 0x10;
+    out.extend_from_slice(&[
+        (ONE_BYTE_PROFILE >> 8) as u8,
+        ONE_BYTE_PROFILE as u8,
+        (words >> 8) as u8,
+        words as u8,
+    ]);
+    out.extend_from_slice(&elements);
+    out.extend_from_slice(&packet[payload_start..]);
+    Some(out)
+}
+
+/// Send times read from a packet's one-byte header extensions.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SendTime {
+    pub seq: u16,
+    pub ntp64: Option<u64>,
+    pub abs_send_time: Option<u32>,
+}
+
+pub fn parse_send_time(packet: &[u8], ids: ExtensionIds) -> Option<SendTime> {
+    let header_len = header_len(packet)?;
+    if packet[0] & 0x10 == 0 || packet.len() < header_len + 4 {
+        return None;
+    }
+    let profile = (packet[header_len] as u16) << 8 | packet[header_len + 1] as u16;
+    let words = (packet[header_len + 2] as usize) << 8 | packet[header_len + 3] as usize;
+    let end = header_len + 4 + 4 * words;
+    if profile != ONE_BYTE_PROFILE || packet.len() < end {
+        return None;
+    }
+
+    let mut send_time = SendTime {
+        seq: (packet[2] as u16) << 8 | packet[3] as u16,
+        ..SendTime::default()
+    };
+
+    let mut pos = header_len + 4;
+    while pos < end {
+        let id = packet[pos] >> 4;
+        let len = (packet[pos] & 0x0F) as usize + 1;
+        if id == 0 {
+            pos += 1;
+            continue;
+        }
+        if id == 15 || pos + 1 + len > end {
+            break;
+        }
+
+        let data = &packet[pos + 1..pos + 1 + len];
+        let value = data.iter().fold(0u64, |acc, &b| acc << 8 | b as u64);
+        if id == ids.ntp64 && len == 8 {
+            send_time.ntp64 = Some(value);
+        } else if id == ids.abs_send_time && len == 3 {
+            send_time.abs_send_time = Some(value as u32);
+        }
+        pos += 1 + len;
+    }
+
+    Some(send_time)
+}
+
+/// Signed difference of two NTP timestamps in ms.
+fn ntp_diff_ms(later: u64, earlier: u64) -> f64 {
+    (later.wrapping_sub(earlier) as i64) as f64 * 1000.0 / 4_294_967_296.0
+}
+
+/// One-way delay of the packets since the last report. Only meaningful
+/// when both hosts are NTP synchronised; the delay variation is not
+/// affected by a constant clock offset.
+#[derive(Debug, Default)]
+pub struct DelayStats {
+    packets: usize,
+    sum_ms: f64,
+    min_ms: f64,
+    max_ms: f64,
+    jitter_ms: f64,
+    last_transit_ms: Option<f64>,
+    log: Option<File>,
+}
+
+impl DelayStats {
+    pub fn add(&mut self, send_time: &SendTime, arrival_ntp: u64) {
+        let delay_ms = match send_time.ntp64 {
+            Some(ntp64) => ntp_diff_ms(arrival_ntp, ntp64),
+            None => match send_time.abs_send_time {
+                // Only the variation of this one is meaningful.
+                Some(ast) => {
+                    let arrival = abs_send_time(arrival_ntp);
+                    let diff = (arrival.wrapping_sub(ast) & 0x00FF_FFFF) as f64;
+                    diff * 1000.0 / 262_144.0
+                }
+                None => return,
+            },
+        };
+
+        if self.packets == 0 || delay_ms < self.min_ms {
+            self.min_ms = delay_ms;
+        }
+        if self.packets == 0 || delay_ms > self.max_ms {
+            self.max_ms = delay_ms;
+        }
+        self.packets += 1;
+        self.sum_ms += delay_ms;
+
+        // Interarrival jitter as in RFC 3550 section 6.4.1
+        if let Some(last) = self.last_transit_ms {
+            self.jitter_ms += ((delay_ms - last).abs() - self.jitter_ms) / 16.0;
+        }
+        self.last_transit_ms = Some(delay_ms);
+
+        if let Some(ref mut log) = self.log {
+            let _ = writeln!(log, "{},{},{:.3}", send_time.seq, arrival_ntp, delay_ms);
+        }
+    }
+
+    pub fn report(&mut self) -> String {
+        let report = if self.packets == 0 {
+            String::from("One-way delay: no timestamped packets")
+        } else {
+            format!(
+                "One-way delay: {} packets, min {:.2} ms, mean {:.2} ms, max {:.2} ms, variation {:.2} ms, jitter {:.2} ms",
+                self.packets,
+                self.min_ms,
+                self.sum_ms / self.packets as f64,
+                self.max_ms,
+                self.max_ms - self.min_ms,
+                self.jitter_ms
+            )
+        };
+
+        self.packets = 0;
+        self.sum_ms = 0.0;
+        report
+    }
+}
+
+/// Stamps every RTP packet leaving `pad` with its send time.
+pub fn attach_stamper(pad: &gst::Pad, ids: ExtensionIds) {
+    pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+        if let Some(gst::PadProbeData::Buffer(ref mut buffer)) = info.data {
+            let stamped = {
+                let map = match buffer.map_readable() {
+                    Some(map) => map,
+                    None => return gst::PadProbeReturn::Ok,
+                };
+                add_send_time(map.as_slice(), ids, ntp_now())
+            };
+
+            if let Some(mut new_buffer) = stamped.and_then(gst::Buffer::from_mut_slice) {
+                {
+                    let new_ref = new_buffer.get_mut().unwrap();
+                    new_ref.set_pts(buffer.get_pts());
+                    new_ref.set_dts(buffer.get_dts());
+                    new_ref.set_duration(buffer.get_duration());
+                    new_ref.set_flags(buffer.get_flags());
+                }
+                *buffer = new_buffer;
+            }
+        }
+        gst::PadProbeReturn::Ok
+    });
+}
+
+/// Measures the one-way delay of the RTP packets arriving through `pad`.
+/// Every packet is also written to `hdrext.log` as `seq,arrival_ntp,delay_ms`
+/// when that key is set.
+pub fn attach_delay_meter(pad: &gst::Pad, config: &Config, ids: ExtensionIds) -> Result<Arc<Mutex<DelayStats>>, Error> {
+    let mut stats = DelayStats::default();
+    if let Some(path) = config.get_str("hdrext.log") {
+        stats.log = Some(File::create(path)?);
+    }
+    let stats = Arc::new(Mutex::new(stats));
+
+    let probe_stats = stats.clone();
+    pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+        if let Some(gst::PadProbeData::Buffer(ref buffer)) = info.data {
+            let arrival = ntp_now();
+            if let Some(map) = buffer.map_readable() {
+                if let Some(send_time) = parse_send_time(map.as_slice(), ids) {
+                    probe_stats.lock().unwrap().add(&send_time, arrival);
+                }
+            }
+        }
+        gst::PadProbeReturn::Ok
+    });
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IDS: ExtensionIds = ExtensionIds {
+        ntp64: 3,
+        abs_send_time: 5,
+    };
+    const NTP: u64 = 0xE1E2_E3E4_1234_5678;
+
+    /// An RTP packet with sequence number 0x1234, one CSRC and `payload`.
+    fn packet(payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x81, 96, 0x12, 0x34, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3];
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn round_trip() {
+        let stamped = add_send_time(&packet(b"opus"), IDS, NTP).unwrap();
+        assert!(stamped.ends_with(b"opus"));
+        assert_eq!((stamped.len() - packet(b"opus").len()) % 4, 0);
+
+        let send_time = parse_send_time(&stamped, IDS).unwrap();
+        assert_eq!(send_time.seq, 0x1234);
+        assert_eq!(send_time.ntp64, Some(NTP));
+        assert_eq!(send_time.abs_send_time, Some(abs_send_time(NTP)));
+    }
+
+    #[test]
+    fn keeps_existing_one_byte_extensions() {
+        let mut existing = packet(b"");
+        existing[0] |= 0x10;
+        existing.extend_from_slice(&[0xBE, 0xDE, 0, 1, 0x10, 0xAA, 0, 0]);
+        existing.extend_from_slice(b"opus");
+
+        let ids = ExtensionIds {
+            ntp64: 3,
+            abs_send_time: 0,
+        };
+        let stamped = add_send_time(&existing, ids, NTP).unwrap();
+        assert!(stamped.ends_with(b"opus"));
+        assert_eq!(&stamped[20..22], &[0x10, 0xAA]);
+        assert_eq!(parse_send_time(&stamped, ids).unwrap().ntp64, Some(NTP));
+    }
+
+    #[test]
+    fn leaves_out_unconfigured_extensions() {
+        let ids = ExtensionIds {
+            ntp64: 0,
+            abs_send_time: 5,
+        };
+        let send_time = parse_send_time(&add_send_time(&packet(b"opus"), ids, NTP).unwrap(), IDS).unwrap();
+        assert_eq!(send_time.ntp64, None);
+        assert_eq!(send_time.abs_send_time, Some(abs_send_time(NTP)));
+    }
+
+    #[test]
+    fn rejects_other_packets() {
+        assert!(add_send_time(b"not rtp", IDS, NTP).is_none());
+        assert!(parse_send_time(&packet(b"opus"), IDS).is_none());
+
+        let mut two_byte = packet(b"");
+        two_byte[0] |= 0x10;
+        two_byte.extend_from_slice(&[0x10, 0x00, 0, 0]);
+        assert!(add_send_time(&two_byte, IDS, NTP).is_none());
+    }
+}
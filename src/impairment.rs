@@ -0,0 +1,85 @@
+use gst;
+use gst::prelude::*;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use failure::Error;
+
+use common::make_element;
+use config::Config;
+use loss::LossModel;
+
+/// Makes a `netsim` stage that impairs the RTP stream for rehearsing how
+/// the FEC and latency settings cope with a bad network. Returns `None`
+/// unless one of the `impair.*` keys is set:
+///
+/// * `impair.drop`, `impair.duplicate`: probability from 0 to 1
+/// * `impair.delay`: added delay in ms
+/// * `impair.jitter`: the delay varies by up to this many ms either way
+/// * `impair.reorder`: let jittered packets overtake each other
+pub fn make_stage(config: &Config) -> Result<Option<gst::Element>, Error> {
+    let drop = config.get_probability("impair.drop", 0.0)? as f32;
+    let duplicate = config.get_probability("impair.duplicate", 0.0)? as f32;
+    let delay = config.get_or("impair.delay", 0i32)?;
+    let jitter = config.get_or("impair.jitter", 0i32)?;
+    let reorder = config.get_or("impair.reorder", false)?;
+
+    if drop == 0.0 && duplicate == 0.0 && delay == 0 && jitter == 0 {
+        return Ok(None);
+    }
+
+    let netsim = make_element("netsim", None)?;
+    netsim.set_property("drop-probability", &drop.to_value())?;
+    netsim.set_property("duplicate-probability", &duplicate.to_value())?;
+    if delay > 0 || jitter > 0 {
+        let min_delay = if delay > jitter { delay - jitter } else { 0 };
+        netsim.set_property("delay-probability", &1.0f32.to_value())?;
+        netsim.set_property("min-delay", &min_delay.to_value())?;
+        netsim.set_property("max-delay", &(delay + jitter).to_value())?;
+    }
+    netsim.set_property("allow-reordering", &reorder.to_value())?;
+
+    eprintln!(
+        "Impairing link: drop {}, duplicate {}, delay {} ms, jitter {} ms, reorder {}",
+        drop, duplicate, delay, jitter, reorder
+    );
+
+    Ok(Some(netsim))
+}
+
+/// Packets seen and dropped by an attached loss model.
+#[derive(Debug, Default)]
+pub struct LossCounts {
+    pub packets: AtomicUsize,
+    pub lost: AtomicUsize,
+}
+
+impl LossCounts {
+    pub fn packets(&self) -> usize {
+        self.packets.load(Ordering::Relaxed)
+    }
+
+    pub fn lost(&self) -> usize {
+        self.lost.load(Ordering::Relaxed)
+    }
+}
+
+/// Drops the buffers flowing through `pad` that `model` decides are lost.
+pub fn attach_loss(pad: &gst::Pad, model: Box<dyn LossModel>) -> Arc<LossCounts> {
+    let counts = Arc::new(LossCounts::default());
+    let model = Mutex::new(model);
+
+    let probe_counts = counts.clone();
+    pad.add_probe(gst::PadProbeType::BUFFER, move |_, _| {
+        probe_counts.packets.fetch_add(1, Ordering::Relaxed);
+        if model.lock().unwrap().lose() {
+            probe_counts.lost.fetch_add(1, Ordering::Relaxed);
+            gst::PadProbeReturn::Drop
+        } else {
+            gst::PadProbeReturn::Ok
+        }
+    });
+
+    counts
+}
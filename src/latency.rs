@@ -0,0 +1,209 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use gst;
+use gst::prelude::*;
+
+use audio;
+
+/// Length of the click the transmitter sends at every interval boundary.
+const PULSE_NS: u64 = 2_000_000;
+const PULSE_AMPLITUDE: f32 = 0.5;
+
+/// Received samples louder than this start a pulse.
+const DETECT_THRESHOLD: f32 = 0.2;
+
+fn unix_now_ns() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System clock before 1970");
+    now.as_secs() * 1_000_000_000 + now.subsec_nanos() as u64
+}
+
+/// Wall clock time, in ns since the Unix epoch, at which the pipeline clock
+/// reaches `clock_time`.
+fn clock_to_wall(element: &gst::Element, clock_time: u64) -> Option<u64> {
+    let clock = element.get_clock()?;
+    let clock_now = clock.get_time().nseconds()?;
+    Some((unix_now_ns() as i64 + clock_time as i64 - clock_now as i64) as u64)
+}
+
+/// Wall clock time of the first sample of `buffer` passing an element. The
+/// running time is taken to be the buffer's PTS, which holds for the live
+/// sources and sinks of these pipelines.
+fn buffer_wall_time(element: &gst::Element, buffer: &gst::BufferRef, extra_ns: u64) -> Option<u64> {
+    let base_time = element.get_base_time().nseconds()?;
+    let pts = buffer.get_pts().nseconds()?;
+    clock_to_wall(element, base_time + pts + extra_ns)
+}
+
+/// Replaces the audio with a short click at every multiple of `interval_ns`
+/// of the wall clock. `start_ns` is the wall clock time of the first frame.
+pub fn insert_pulses(interval_ns: u64, start_ns: u64, rate: u32, channels: usize, samples: &mut [f32]) {
+    for (frame, chunk) in samples.chunks_mut(channels).enumerate() {
+        let t = start_ns + frame as u64 * 1_000_000_000 / rate as u64;
+        let value = if t % interval_ns < PULSE_NS { PULSE_AMPLITUDE } else { 0.0 };
+        for sample in chunk {
+            *sample = value;
+        }
+    }
+}
+
+/// Latency statistics of the pulses detected since the last report.
+#[derive(Debug, Clone, Default)]
+pub struct LatencyReport {
+    pub pulses: usize,
+    pub min_ms: f64,
+    pub max_ms: f64,
+    pub mean_ms: f64,
+    pub stddev_ms: f64,
+}
+
+impl fmt::Display for LatencyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.pulses == 0 {
+            return write!(f, "no pulses detected");
+        }
+        write!(
+            f,
+            "{} pulses, min {:.1} ms, mean {:.1} ms, max {:.1} ms, stddev {:.1} ms",
+            self.pulses, self.min_ms, self.mean_ms, self.max_ms, self.stddev_ms
+        )
+    }
+}
+
+/// Finds the clicks `insert_pulses` sends and measures how long after their
+/// interval boundary they are played out.
+#[derive(Debug)]
+pub struct PulseDetector {
+    interval_ns: u64,
+    offset_ns: u64,
+    latency_ns: u64,
+    holdoff_until: u64,
+    latencies_ms: Vec<f64>,
+}
+
+impl PulseDetector {
+    pub fn new(interval_ns: u64, offset_ns: u64) -> PulseDetector {
+        PulseDetector {
+            interval_ns,
+            offset_ns,
+            latency_ns: 0,
+            holdoff_until: 0,
+            latencies_ms: Vec::new(),
+        }
+    }
+
+    /// Feeds interleaved samples whose first frame is played out at wall
+    /// clock time `start_ns`. Latencies are only unambiguous while they
+    /// stay below the interval.
+    pub fn push(&mut self, start_ns: u64, rate: u32, channels: usize, samples: &[f32]) {
+        for (frame, chunk) in samples.chunks(channels).enumerate() {
+            let t = start_ns + frame as u64 * 1_000_000_000 / rate as u64;
+            if t < self.holdoff_until || chunk[0].abs() < DETECT_THRESHOLD {
+                continue;
+            }
+
+            let latency_ns = t % self.interval_ns;
+            self.latencies_ms.push(latency_ns as f64 / 1_000_000.0);
+            self.holdoff_until = t + self.interval_ns / 2;
+        }
+    }
+
+    /// Time from a buffer's running time until it is played out.
+    pub fn playout_delay_ns(&self) -> u64 {
+        self.latency_ns + self.offset_ns
+    }
+
+    pub fn set_pipeline_latency(&mut self, latency_ns: u64) {
+        self.latency_ns = latency_ns;
+    }
+
+    pub fn take_report(&mut self) -> LatencyReport {
+        let latencies = &self.latencies_ms;
+        let mut report = LatencyReport::default();
+
+        if !latencies.is_empty() {
+            let n = latencies.len() as f64;
+            report.pulses = latencies.len();
+            report.mean_ms = latencies.iter().sum::<f64>() / n;
+            report.min_ms = latencies.iter().cloned().fold(f64::INFINITY, f64::min);
+            report.max_ms = latencies.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+            report.stddev_ms = (latencies
+                .iter()
+                .map(|l| (l - report.mean_ms) * (l - report.mean_ms))
+                .sum::<f64>()
+                / n)
+                .sqrt();
+        }
+
+        self.latencies_ms.clear();
+        report
+    }
+}
+
+/// Replaces the audio passing `pad` with pulses timed on the wall clock.
+pub fn attach_pulse_inserter(pad: &gst::Pad, interval_ns: u64) {
+    pad.add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+        let element = match pad.get_parent_element() {
+            Some(element) => element,
+            None => return gst::PadProbeReturn::Ok,
+        };
+
+        if let Some(gst::PadProbeData::Buffer(ref mut buffer)) = info.data {
+            let buffer = buffer.make_mut();
+            if let (Some(mut block), Some(start)) = (
+                audio::read_block(pad, buffer),
+                buffer_wall_time(&element, buffer, 0),
+            ) {
+                insert_pulses(interval_ns, start, block.rate, block.channels, &mut block.samples);
+                audio::write_block(pad, buffer, &block.samples);
+            }
+        }
+        gst::PadProbeReturn::Ok
+    });
+}
+
+/// Detects pulses in the audio passing `pad`, the sink pad of the audio
+/// sink. Buffers are played out the pipeline latency after their running
+/// time, which must be kept up to date with `set_pipeline_latency`;
+/// `offset_ns` adds what the pipeline does not know about, like the sound
+/// card.
+pub fn attach_pulse_detector(pad: &gst::Pad, interval_ns: u64, offset_ns: u64) -> Arc<Mutex<PulseDetector>> {
+    let detector = Arc::new(Mutex::new(PulseDetector::new(interval_ns, offset_ns)));
+
+    let probe_detector = detector.clone();
+    pad.add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+        let element = match pad.get_parent_element() {
+            Some(element) => element,
+            None => return gst::PadProbeReturn::Ok,
+        };
+
+        if let Some(gst::PadProbeData::Buffer(ref buffer)) = info.data {
+            let mut detector = probe_detector.lock().unwrap();
+            let delay_ns = detector.playout_delay_ns();
+            if let (Some(block), Some(start)) = (
+                audio::read_block(pad, buffer),
+                buffer_wall_time(&element, buffer, delay_ns),
+            ) {
+                detector.push(start, block.rate, block.channels, &block.samples);
+            }
+        }
+        gst::PadProbeReturn::Ok
+    });
+
+    detector
+}
+
+/// Latency the pipeline configured on its sinks, 0 until it is playing.
+pub fn pipeline_latency(pipeline: &gst::Pipeline) -> u64 {
+    let mut query = gst::Query::new_latency();
+    if !pipeline.query(query.get_mut().unwrap()) {
+        return 0;
+    }
+    match query.view() {
+        gst::QueryView::Latency(ref latency) => latency.get_result().1.nseconds().unwrap_or(0),
+        _ => 0,
+    }
+}
@@ -0,0 +1,128 @@
+use glib;
+use gst;
+use gst::prelude::*;
+
+use std::fmt;
+use std::sync::Mutex;
+
+use failure::Error;
+
+use common::make_element;
+use config::Config;
+
+/// Per-channel levels in dBFS from one message of a `level` element.
+#[derive(Debug, Clone, Default)]
+pub struct Levels {
+    pub peak: Vec<f64>,
+    pub rms: Vec<f64>,
+    pub decay: Vec<f64>,
+}
+
+fn format_channels(values: &[f64]) -> String {
+    values
+        .iter()
+        .map(|value| format!("{:.1}", value))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+impl fmt::Display for Levels {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "peak {} dBFS, rms {} dBFS, decay {} dBFS",
+            format_channels(&self.peak),
+            format_channels(&self.rms),
+            format_channels(&self.decay)
+        )
+    }
+}
+
+/// The latest levels of every `level` element in a pipeline, for the
+/// periodic stats. The bus handler updates it as messages arrive.
+#[derive(Debug, Default)]
+pub struct LevelStats {
+    latest: Mutex<Vec<(String, Levels)>>,
+}
+
+impl LevelStats {
+    pub fn update(&self, name: &str, levels: &Levels) {
+        let mut latest = self.latest.lock().unwrap();
+        match latest.iter_mut().find(|entry| entry.0 == name) {
+            Some(entry) => entry.1 = levels.clone(),
+            None => latest.push((name.to_string(), levels.clone())),
+        }
+    }
+
+    /// The last levels of each element, in the order they first posted.
+    pub fn latest(&self) -> Vec<(String, Levels)> {
+        self.latest.lock().unwrap().clone()
+    }
+}
+
+/// Makes a `level` element called `name`. It posts a message every
+/// `level.interval` ms, one second by default.
+pub fn make_level(config: &Config, name: &str) -> Result<gst::Element, Error> {
+    let level = make_element("level", name)?;
+    let interval_ms = config.get_or("level.interval", 1000u64)?;
+    level.set_property("interval", &(interval_ms * 1_000_000).to_value())?;
+    level.set_property("post-messages", &true.to_value())?;
+    Ok(level)
+}
+
+fn channel_values(structure: &gst::StructureRef, field: &str) -> Vec<f64> {
+    structure
+        .get::<glib::ValueArray>(field)
+        .map(|array| array.iter().filter_map(|value| value.get::<f64>()).collect())
+        .unwrap_or_else(Vec::new)
+}
+
+/// Reads the levels from an element message posted by a `level` element,
+/// together with the name of the element.
+pub fn parse_message(msg: &gst::Message) -> Option<(String, Levels)> {
+    let structure = match msg.view() {
+        gst::MessageView::Element(element) => element.get_structure()?,
+        _ => return None,
+    };
+    if structure.get_name() != "level" {
+        return None;
+    }
+
+    let name = msg.get_src().map(|src| src.get_name()).unwrap_or_default();
+    Some((
+        name,
+        Levels {
+            peak: channel_values(structure, "peak"),
+            rms: channel_values(structure, "rms"),
+            decay: channel_values(structure, "decay"),
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn levels(peak: f64) -> Levels {
+        Levels {
+            peak: vec![peak, peak],
+            rms: vec![peak - 3.0, peak - 3.0],
+            decay: vec![peak, peak],
+        }
+    }
+
+    #[test]
+    fn keeps_the_latest_levels_of_each_element() {
+        let stats = LevelStats::default();
+        stats.update("level", &levels(-20.0));
+        stats.update("linklevel", &levels(-30.0));
+        stats.update("level", &levels(-10.0));
+
+        let latest = stats.latest();
+        assert_eq!(latest.len(), 2);
+        assert_eq!(latest[0].0, "level");
+        assert_eq!(latest[0].1.peak, vec![-10.0, -10.0]);
+        assert_eq!(latest[1].0, "linklevel");
+        assert_eq!(latest[1].1.rms, vec![-33.0, -33.0]);
+    }
+}
@@ -0,0 +1,38 @@
+//! The modules shared by the transmitter, the receiver and the tools.
+
+extern crate byte_slice_cast;
+extern crate glib;
+#[macro_use]
+extern crate gstreamer as gst;
+extern crate gstreamer_app as gst_app;
+extern crate libc;
+
+extern crate failure;
+#[macro_use]
+extern crate failure_derive;
+
+pub mod archive;
+pub mod audio;
+pub mod common;
+pub mod conceal;
+pub mod config;
+pub mod control;
+pub mod delay;
+pub mod fallback;
+pub mod gain;
+pub mod hdrext;
+pub mod impairment;
+pub mod latency;
+pub mod level;
+pub mod link;
+pub mod loss;
+pub mod loudness;
+pub mod prbs;
+pub mod rtp;
+pub mod rtpdump;
+pub mod selector;
+pub mod silence;
+pub mod snapshot;
+pub mod srtp;
+pub mod tone;
+pub mod transport;
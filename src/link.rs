@@ -0,0 +1,288 @@
+use gst;
+use gst::prelude::*;
+
+use std::sync::{Arc, Mutex};
+
+use failure::Error;
+
+use common::{get_request_pad, get_static_pad, make_element};
+use config::Config;
+use hdrext::{self, DelayStats};
+use impairment::{self, LossCounts};
+use loss;
+use prbs::{self, PcmTest};
+use rtp;
+use rtpdump::{self, RtpdumpWriter};
+use srtp;
+use transport;
+
+/// FEC overhead of the transmitter in percent of the media packets, from
+/// its command line.
+#[derive(Debug, Clone, Copy)]
+pub struct FecPercentages {
+    pub percentage: u32,
+    pub important: u32,
+}
+
+/// The transmitter's RTP side, set up by `make_transmitter`.
+pub struct TransmitterLink {
+    pub rtpbin: gst::Element,
+    /// rtpdump capture of the sent packets, from `capture.file`.
+    pub capture: Option<Arc<Mutex<RtpdumpWriter>>>,
+}
+
+/// Sends the RTP from `payloader` through an rtpbin adding FEC, and SRTP
+/// if configured, to `address`:`port` over the configured transport, with
+/// the impairment stage in between if one is configured.
+pub fn make_transmitter(
+    pipeline: &gst::Pipeline,
+    config: &Config,
+    payloader: &gst::Element,
+    address: &str,
+    port: i32,
+    sync: bool,
+    fec: FecPercentages,
+) -> Result<TransmitterLink, Error> {
+    let rtpbin = make_element("rtpbin", None)?;
+    let netsink = transport::make_transmitter_sink(config, address, port, sync)?;
+    pipeline.add_many(&[&rtpbin, &netsink])?;
+
+    rtpbin.connect("request-fec-encoder", false, move |values| {
+        let rtpbin = values[0].get::<gst::Element>().expect("Invalid argument");
+
+        match rtp::make_fec_encoder(fec.percentage, fec.important) {
+            Ok(elem) => Some(elem.to_value()),
+            Err(err) => {
+                gst_element_error!(
+                    rtpbin,
+                    gst::LibraryError::Failed,
+                    ("Failed to make FEC encoder"),
+                    ["{}", err]
+                );
+                None
+            }
+        }
+    })?;
+
+    if let Some(srtp) = srtp::SrtpSettings::from_config(config)? {
+        let key = srtp.key.clone();
+        rtpbin.connect("request-rtp-encoder", false, move |values| {
+            let rtpbin = values[0].get::<gst::Element>().expect("Invalid argument");
+
+            match srtp::make_encoder(&key) {
+                Ok(elem) => Some(elem.to_value()),
+                Err(err) => {
+                    gst_element_error!(
+                        rtpbin,
+                        gst::LibraryError::Failed,
+                        ("Failed to make SRTP encoder"),
+                        ["{}", err]
+                    );
+                    None
+                }
+            }
+        })?;
+
+        let pipelineclone = pipeline.clone();
+        srtp.watch_key_file(move |key| {
+            if let Some(srtpenc) = pipelineclone.get_by_name("srtpenc") {
+                if let Err(err) = srtp::set_encoder_key(&srtpenc, &key) {
+                    eprintln!("Failed to rotate SRTP key: {}", err);
+                }
+            }
+        });
+    }
+
+    let srcpad = get_static_pad(payloader, "src")?;
+    let sinkpad = get_request_pad(&rtpbin, "send_rtp_sink_0")?;
+    srcpad.link(&sinkpad).into_result()?;
+
+    let srcpad = get_static_pad(&rtpbin, "send_rtp_src_0")?;
+    let capture = match config.get_str("capture.file") {
+        Some(path) => Some(rtpdump::attach_capture(&srcpad, path, port)?),
+        None => None,
+    };
+    let rtp_sink = match impairment::make_stage(config)? {
+        Some(netsim) => {
+            pipeline.add(&netsim)?;
+            netsim.link(&netsink)?;
+            netsim
+        }
+        None => netsink,
+    };
+    let sinkpad = get_static_pad(&rtp_sink, "sink")?;
+    srcpad.link(&sinkpad).into_result()?;
+
+    Ok(TransmitterLink { rtpbin, capture })
+}
+
+/// The receiver's RTP side, set up by `make_receiver`.
+pub struct ReceiverLink {
+    pub rtpbin: gst::Element,
+    /// The network sources of the primary and, if any, the backup link.
+    pub netsrcs: Vec<gst::Element>,
+    /// Packets dropped by the `loss.*` model in front of the rtpbin.
+    pub injected_loss: Option<Arc<LossCounts>>,
+    /// One-way delay from the header extension timestamps.
+    pub one_way_delay: Option<Arc<Mutex<DelayStats>>>,
+}
+
+/// Receives the primary link on `port`, and the backup link on
+/// `backup_port` if given, into an rtpbin that recovers packets with FEC,
+/// decrypts SRTP if configured and hands session 0 to the first of
+/// `depays` and session 1 to the second. The jitterbuffer waits `latency`
+/// ms and the FEC storage keeps `size_time_ms` ms of packets.
+pub fn make_receiver(
+    pipeline: &gst::Pipeline,
+    config: &Config,
+    port: i32,
+    backup_port: Option<i32>,
+    latency: u32,
+    size_time_ms: u64,
+    depays: Vec<gst::Element>,
+) -> Result<ReceiverLink, Error> {
+    let srtp = srtp::SrtpSettings::from_config(config)?;
+    let pcm_test = PcmTest::from_config(config)?;
+    let rtp_caps = match srtp {
+        Some(_) => gst::Caps::new_simple("application/x-srtp", &[("clock-rate", &48000i32)]),
+        None => gst::Caps::new_simple("application/x-rtp", &[("clock-rate", &48000i32)]),
+    };
+
+    let netsrc = transport::make_receiver_source(config, port, &rtp_caps)?;
+    let rtpbin = make_element("rtpbin", None)?;
+    pipeline.add_many(&[&netsrc, &rtpbin])?;
+    let mut netsrcs = vec![netsrc.clone()];
+
+    // A backup transmitter sends the same programme to `backup.port`, over
+    // the transport and NAT settings given as `backup.<key>` where they
+    // differ from the primary's, e.g. `backup.nat.peer`.
+    if let Some(backup_port) = backup_port {
+        let backup_config = config.with_section("backup");
+        let backup_netsrc = transport::make_receiver_source(&backup_config, backup_port, &rtp_caps)?;
+        pipeline.add(&backup_netsrc)?;
+        let srcpad = get_static_pad(&backup_netsrc, "src")?;
+        let sinkpad = get_request_pad(&rtpbin, "recv_rtp_sink_1")?;
+        srcpad.link(&sinkpad).into_result()?;
+        netsrcs.push(backup_netsrc);
+    }
+
+    rtpbin.connect("new-storage", false, move |values| {
+        let storage = values[1].get::<gst::Element>().expect("Invalid argument");
+        let size_time_ns = size_time_ms * 1_000_000;
+        storage
+            .set_property("size-time", &size_time_ns.to_value())
+            .unwrap();
+
+        None
+    })?;
+
+    rtpbin.connect("request-pt-map", false, move |values| {
+        let pt = values[2].get::<u32>().expect("Invalid argument");
+        match pt {
+            prbs::PCM_PT => pcm_test.map(|test| test.rtp_caps().to_value()),
+            pt => rtp::pt_caps(pt).map(|caps| caps.to_value()),
+        }
+    })?;
+
+    if let Some(ref srtp) = srtp {
+        let key = Arc::new(Mutex::new(srtp.key.clone()));
+        // One monitor per RTP session, in the order of `connect_rtpbin_srcpad`.
+        let links: &[&str] = if backup_port.is_some() { &["primary", "backup"] } else { &["primary"] };
+        let monitors = links.iter().map(|link| srtp::AuthMonitor::new(link)).collect::<Vec<_>>();
+        for monitor in &monitors {
+            monitor.spawn();
+        }
+
+        let decoder_key = key.clone();
+        rtpbin.connect("request-rtp-decoder", false, move |values| {
+            let rtpbin = values[0].get::<gst::Element>().expect("Invalid argument");
+            let sess_id = values[1].get::<u32>().expect("Invalid argument");
+
+            let decoder = match monitors.get(sess_id as usize) {
+                Some(monitor) => srtp::make_decoder(&decoder_key, monitor, sess_id),
+                None => Err(Error::from(rtp::UnknownSession(sess_id as usize))),
+            };
+            match decoder {
+                Ok(elem) => Some(elem.to_value()),
+                Err(err) => {
+                    gst_element_error!(
+                        rtpbin,
+                        gst::LibraryError::Failed,
+                        ("Failed to make SRTP decoder"),
+                        ["{}", err]
+                    );
+                    None
+                }
+            }
+        })?;
+
+        let pipelineclone = pipeline.clone();
+        srtp.watch_key_file(move |new_key| {
+            *key.lock().unwrap() = new_key;
+            for session in 0..2 {
+                if let Some(srtpdec) = pipelineclone.get_by_name(&srtp::decoder_name(session)) {
+                    srtp::clear_decoder_keys(&srtpdec);
+                }
+            }
+        });
+    }
+
+    let rtp_src = match impairment::make_stage(config)? {
+        Some(netsim) => {
+            pipeline.add(&netsim)?;
+            netsrc.link(&netsim)?;
+            netsim
+        }
+        None => netsrc,
+    };
+    let srcpad = get_static_pad(&rtp_src, "src")?;
+    let injected_loss = match loss::from_config(config)? {
+        Some(model) => Some(impairment::attach_loss(&srcpad, model)),
+        None => None,
+    };
+    let one_way_delay = match hdrext::ExtensionIds::from_config(config)? {
+        Some(ids) => Some(hdrext::attach_delay_meter(&srcpad, config, ids)?),
+        None => None,
+    };
+    let sinkpad = get_request_pad(&rtpbin, "recv_rtp_sink_0")?;
+    srcpad.link(&sinkpad).into_result()?;
+
+    rtpbin.connect_pad_added(move |rtpbin, src_pad| {
+        if let Err(err) = rtp::connect_rtpbin_srcpad(rtpbin, src_pad, &depays) {
+            gst_element_error!(
+                rtpbin,
+                gst::LibraryError::Failed,
+                ("Failed to link srcpad"),
+                ["{}", err]
+            );
+        }
+    });
+
+    rtpbin.connect("request-fec-decoder", false, |values| {
+        let rtpbin = values[0].get::<gst::Element>().expect("Invalid argument");
+        let sess_id = values[1].get::<u32>().expect("Invalid argument");
+        println!("Requesting fecdec");
+        match rtp::make_fec_decoder(&rtpbin, sess_id) {
+            Ok(elem) => Some(elem.to_value()),
+            Err(err) => {
+                gst_element_error!(
+                    rtpbin,
+                    gst::LibraryError::Failed,
+                    ("Failed to make FEC decoder"),
+                    ["{}", err]
+                );
+                None
+            }
+        }
+    })?;
+
+    rtpbin.set_property("do-lost", &true.to_value())?;
+    rtpbin.set_property("latency", &latency.to_value())?;
+
+    Ok(ReceiverLink {
+        rtpbin,
+        netsrcs,
+        injected_loss,
+        one_way_delay,
+    })
+}
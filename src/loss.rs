@@ -0,0 +1,220 @@
+use std::fs::File;
+use std::io::Read;
+
+use failure::Error;
+
+use config::{Config, ConfigValueError};
+
+#[derive(Debug, Fail)]
+#[fail(display = "Loss trace {} has no packets", _0)]
+pub struct EmptyTrace(String);
+
+/// Decides packet by packet whether a packet is lost.
+pub trait LossModel: Send {
+    fn lose(&mut self) -> bool;
+}
+
+/// xorshift64* generator, so that a loss run can be repeated from its seed.
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        // Zero is the one state xorshift never leaves.
+        Rng(if seed == 0 { 0x9e37_79b9_7f4a_7c15 } else { seed })
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Uniform in [0, 1).
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Two-state Gilbert-Elliott channel. In the good state packets are lost
+/// with probability `good_loss`, in the bad state with `bad_loss`. After
+/// each packet the channel moves from good to bad with probability `p` and
+/// from bad to good with probability `r`, so losses come in bursts of mean
+/// length around 1 / `r` when `bad_loss` is high.
+#[derive(Debug, Clone)]
+pub struct GilbertElliott {
+    p: f64,
+    r: f64,
+    good_loss: f64,
+    bad_loss: f64,
+    bad: bool,
+    rng: Rng,
+}
+
+impl GilbertElliott {
+    pub fn new(p: f64, r: f64, good_loss: f64, bad_loss: f64, seed: u64) -> GilbertElliott {
+        GilbertElliott {
+            p,
+            r,
+            good_loss,
+            bad_loss,
+            bad: false,
+            rng: Rng::new(seed),
+        }
+    }
+
+    /// Long-run fraction of packets lost.
+    pub fn mean_loss(&self) -> f64 {
+        if self.p + self.r == 0.0 {
+            return self.good_loss;
+        }
+        let bad_share = self.p / (self.p + self.r);
+        (1.0 - bad_share) * self.good_loss + bad_share * self.bad_loss
+    }
+}
+
+impl LossModel for GilbertElliott {
+    fn lose(&mut self) -> bool {
+        let loss = if self.bad { self.bad_loss } else { self.good_loss };
+        let lost = self.rng.next_f64() < loss;
+
+        let switch = if self.bad { self.r } else { self.p };
+        if self.rng.next_f64() < switch {
+            self.bad = !self.bad;
+        }
+
+        lost
+    }
+}
+
+/// Loss pattern replayed from a trace, looping at the end. In the trace
+/// `1` marks a lost packet and `0` a received one; other characters are
+/// ignored and `#` starts a comment that runs to the end of the line.
+#[derive(Debug, Clone)]
+pub struct TraceLoss {
+    pattern: Vec<bool>,
+    position: usize,
+}
+
+impl TraceLoss {
+    pub fn parse(trace: &str) -> TraceLoss {
+        let pattern = trace
+            .lines()
+            .flat_map(|line| line.split('#').next().unwrap_or("").chars())
+            .filter_map(|c| match c {
+                '1' => Some(true),
+                '0' => Some(false),
+                _ => None,
+            })
+            .collect();
+
+        TraceLoss {
+            pattern,
+            position: 0,
+        }
+    }
+
+    pub fn load(path: &str) -> Result<TraceLoss, Error> {
+        let mut trace = String::new();
+        File::open(path)?.read_to_string(&mut trace)?;
+
+        let loss = TraceLoss::parse(&trace);
+        if loss.pattern.is_empty() {
+            return Err(Error::from(EmptyTrace(path.to_string())));
+        }
+        Ok(loss)
+    }
+}
+
+impl LossModel for TraceLoss {
+    fn lose(&mut self) -> bool {
+        if self.pattern.is_empty() {
+            return false;
+        }
+        let lost = self.pattern[self.position];
+        self.position = (self.position + 1) % self.pattern.len();
+        lost
+    }
+}
+
+/// Loss model from the `loss.*` config keys, or `None` when `loss.model` is
+/// not set. `gilbert-elliott` uses `loss.p`, `loss.r`, `loss.good`,
+/// `loss.bad` and `loss.seed`; `trace` reads the pattern from `loss.trace`.
+pub fn from_config(config: &Config) -> Result<Option<Box<dyn LossModel>>, Error> {
+    match config.get_str("loss.model") {
+        None => Ok(None),
+        Some("gilbert-elliott") => {
+            let model = GilbertElliott::new(
+                config.get_probability("loss.p", 0.01)?,
+                config.get_probability("loss.r", 0.3)?,
+                config.get_probability("loss.good", 0.0)?,
+                config.get_probability("loss.bad", 1.0)?,
+                config.get_or("loss.seed", 1u64)?,
+            );
+            eprintln!(
+                "Injecting Gilbert-Elliott loss, {:.2} % on average",
+                model.mean_loss() * 100.0
+            );
+            Ok(Some(Box::new(model)))
+        }
+        Some("trace") => match config.get_str("loss.trace") {
+            Some(path) => Ok(Some(Box::new(TraceLoss::load(path)?))),
+            None => Err(Error::from(ConfigValueError("loss.trace".into(), "missing".into()))),
+        },
+        Some(other) => Err(Error::from(ConfigValueError("loss.model".into(), other.into()))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fraction of `packets` packets lost and the mean length of the
+    /// bursts of consecutive losses.
+    fn run(model: &mut dyn LossModel, packets: usize) -> (f64, f64) {
+        let (mut lost, mut bursts, mut previous) = (0, 0, false);
+        for _ in 0..packets {
+            let current = model.lose();
+            if current {
+                lost += 1;
+                if !previous {
+                    bursts += 1;
+                }
+            }
+            previous = current;
+        }
+        (lost as f64 / packets as f64, lost as f64 / bursts.max(1) as f64)
+    }
+
+    #[test]
+    fn gilbert_elliott_loss_and_bursts() {
+        let mut model = GilbertElliott::new(0.05, 0.25, 0.0, 1.0, 1234);
+        let (ratio, burst) = run(&mut model, 200_000);
+        assert!((ratio - model.mean_loss()).abs() < 0.01, "loss ratio {}", ratio);
+        assert!((burst - 4.0).abs() < 0.2, "mean burst {}", burst);
+    }
+
+    #[test]
+    fn gilbert_elliott_repeats_from_seed() {
+        let mut first = GilbertElliott::new(0.1, 0.5, 0.01, 0.8, 42);
+        let mut second = first.clone();
+        for _ in 0..10_000 {
+            assert_eq!(first.lose(), second.lose());
+        }
+    }
+
+    #[test]
+    fn trace_loss_loops_the_pattern() {
+        let mut model = TraceLoss::parse("# two in a row\n0110 0000 # then none\n00");
+        let (ratio, burst) = run(&mut model, 1000);
+        assert!((ratio - 0.2).abs() < 1e-9, "loss ratio {}", ratio);
+        assert!((burst - 2.0).abs() < 1e-9, "mean burst {}", burst);
+    }
+
+    #[test]
+    fn empty_trace_loses_nothing() {
+        let mut model = TraceLoss::parse("# nothing\n");
+        assert!(!(0..100).any(|_| model.lose()));
+    }
+}
@@ -0,0 +1,490 @@
+use gst;
+
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use failure::Error;
+
+use audio;
+use config::Config;
+use control::{Control, UnknownCommand};
+
+/// Loudness blocks are measured in steps of 100 ms.
+const STEP_MS: usize = 100;
+/// The momentary window is 400 ms, the short-term window 3 s.
+const MOMENTARY_STEPS: usize = 4;
+const SHORT_TERM_STEPS: usize = 30;
+
+const ABSOLUTE_GATE: f64 = -70.0;
+const RELATIVE_GATE: f64 = -10.0;
+/// Gating blocks are kept in a histogram of 0.1 LU bins from the absolute
+/// gate up to +10 LUFS, so the integrated loudness of a programme running
+/// for days needs no more memory than that of a minute.
+const BINS_PER_LU: f64 = 10.0;
+const BINS: usize = 800;
+
+/// True peak is measured at four times the sample rate.
+const OVERSAMPLING: usize = 4;
+const TAPS_PER_PHASE: usize = 12;
+
+fn energy_to_lufs(energy: f64) -> f64 {
+    if energy <= 0.0 {
+        ::std::f64::NEG_INFINITY
+    } else {
+        -0.691 + 10.0 * energy.log10()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[1] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[2] * y;
+        y
+    }
+}
+
+/// The two stages of the ITU-R BS.1770 K-weighting filter, a high shelf
+/// for the head followed by the RLB high-pass, designed for `rate`.
+fn k_weighting(rate: u32) -> [Biquad; 2] {
+    let rate = f64::from(rate);
+
+    let f0 = 1681.974450955533;
+    let gain_db = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain_db / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+        a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2],
+    };
+
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let highpass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2],
+    };
+
+    [shelf, highpass]
+}
+
+/// Blackman windowed sinc interpolator, split into its `OVERSAMPLING`
+/// phases, each normalised to unity gain. The sinc is centred on a tap so
+/// that the first phase passes the samples themselves through and the
+/// others fall evenly between them.
+fn oversampling_phases() -> Vec<[f64; TAPS_PER_PHASE]> {
+    let taps = OVERSAMPLING * TAPS_PER_PHASE;
+    let center = (taps / 2) as f64;
+    let mut phases = vec![[0.0; TAPS_PER_PHASE]; OVERSAMPLING];
+    for n in 0..taps {
+        let x = (n as f64 - center) / OVERSAMPLING as f64;
+        let sinc = if x == 0.0 { 1.0 } else { (PI * x).sin() / (PI * x) };
+        let w = PI * n as f64 / center;
+        let window = 0.42 - 0.5 * w.cos() + 0.08 * (2.0 * w).cos();
+        phases[n % OVERSAMPLING][n / OVERSAMPLING] = sinc * window;
+    }
+    for phase in &mut phases {
+        let sum = phase.iter().sum::<f64>();
+        for tap in phase.iter_mut() {
+            *tap /= sum;
+        }
+    }
+    phases
+}
+
+/// Momentary, short-term and integrated loudness in LUFS and the true peak
+/// in dBTP, as in EBU R128. The momentary and short-term loudness are -inf
+/// until a whole window of programme has been measured.
+#[derive(Debug, Clone, Copy)]
+pub struct LoudnessReport {
+    pub momentary: f64,
+    pub short_term: f64,
+    pub integrated: f64,
+    pub true_peak: f64,
+}
+
+fn format_level(value: f64) -> String {
+    if value.is_finite() {
+        format!("{:.1}", value)
+    } else {
+        String::from("-inf")
+    }
+}
+
+impl fmt::Display for LoudnessReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "M {} LUFS, S {} LUFS, I {} LUFS, true peak {} dBTP",
+            format_level(self.momentary),
+            format_level(self.short_term),
+            format_level(self.integrated),
+            format_level(self.true_peak)
+        )
+    }
+}
+
+/// EBU R128 loudness meter following ITU-R BS.1770-4. All channels are
+/// weighted equally, which is right for the mono and stereo programmes
+/// the link carries.
+#[derive(Debug)]
+pub struct LoudnessMeter {
+    rate: u32,
+    channels: usize,
+    filters: Vec<[Biquad; 2]>,
+    step_frames: usize,
+    step_position: usize,
+    step_energy: Vec<f64>,
+    steps: VecDeque<f64>,
+    histogram: Vec<(f64, u64)>,
+    phases: Vec<[f64; TAPS_PER_PHASE]>,
+    history: Vec<VecDeque<f64>>,
+    peak: f64,
+}
+
+impl LoudnessMeter {
+    pub fn new() -> LoudnessMeter {
+        LoudnessMeter {
+            rate: 0,
+            channels: 0,
+            filters: Vec::new(),
+            step_frames: 0,
+            step_position: 0,
+            step_energy: Vec::new(),
+            steps: VecDeque::new(),
+            histogram: vec![(0.0, 0); BINS],
+            phases: oversampling_phases(),
+            history: Vec::new(),
+            peak: 0.0,
+        }
+    }
+
+    /// Starts the measurement over, as after a change of format.
+    pub fn reset(&mut self) {
+        let (rate, channels) = (self.rate, self.channels);
+        *self = LoudnessMeter::new();
+        self.configure(rate, channels);
+    }
+
+    fn configure(&mut self, rate: u32, channels: usize) {
+        self.rate = rate;
+        self.channels = channels;
+        self.filters = vec![k_weighting(rate); channels];
+        self.step_frames = rate as usize * STEP_MS / 1000;
+        self.step_position = 0;
+        self.step_energy = vec![0.0; channels];
+        self.steps.clear();
+        self.history = vec![VecDeque::from(vec![0.0; TAPS_PER_PHASE]); channels];
+    }
+
+    /// Feeds interleaved samples.
+    pub fn push(&mut self, rate: u32, channels: usize, samples: &[f32]) {
+        if rate != self.rate || channels != self.channels {
+            self.configure(rate, channels);
+        }
+        if rate == 0 || channels == 0 {
+            return;
+        }
+
+        for frame in samples.chunks(channels) {
+            for (channel, &sample) in frame.iter().enumerate() {
+                let x = f64::from(sample);
+                self.true_peak(channel, x);
+                let filters = &mut self.filters[channel];
+                let shelved = filters[0].process(x);
+                let y = filters[1].process(shelved);
+                self.step_energy[channel] += y * y;
+            }
+
+            self.step_position += 1;
+            if self.step_position == self.step_frames {
+                self.end_step();
+            }
+        }
+    }
+
+    fn true_peak(&mut self, channel: usize, x: f64) {
+        let history = &mut self.history[channel];
+        history.pop_back();
+        history.push_front(x);
+        for phase in &self.phases {
+            let y = phase.iter().zip(history.iter()).map(|(h, x)| h * x).sum::<f64>();
+            if y.abs() > self.peak {
+                self.peak = y.abs();
+            }
+        }
+    }
+
+    fn end_step(&mut self) {
+        let energy = self.step_energy.iter().sum::<f64>() / self.step_frames as f64;
+        for channel_energy in &mut self.step_energy {
+            *channel_energy = 0.0;
+        }
+        self.step_position = 0;
+
+        self.steps.push_back(energy);
+        if self.steps.len() > SHORT_TERM_STEPS {
+            self.steps.pop_front();
+        }
+
+        // Every step completes a 400 ms gating block overlapping the
+        // previous one by 75 %.
+        if self.steps.len() >= MOMENTARY_STEPS {
+            let block = self.window_energy(MOMENTARY_STEPS);
+            let loudness = energy_to_lufs(block);
+            if loudness > ABSOLUTE_GATE {
+                let bin = (((loudness - ABSOLUTE_GATE) * BINS_PER_LU) as usize).min(BINS - 1);
+                self.histogram[bin].0 += block;
+                self.histogram[bin].1 += 1;
+            }
+        }
+    }
+
+    /// Mean energy of the last `steps` steps, or zero before there have
+    /// been that many.
+    fn window_energy(&self, steps: usize) -> f64 {
+        if steps == 0 || self.steps.len() < steps {
+            return 0.0;
+        }
+        self.steps.iter().rev().take(steps).sum::<f64>() / steps as f64
+    }
+
+    fn integrated(&self) -> f64 {
+        let mean = |from: usize| {
+            let (energy, count) = self.histogram[from..]
+                .iter()
+                .fold((0.0, 0), |(energy, count), &(e, c)| (energy + e, count + c));
+            if count == 0 {
+                0.0
+            } else {
+                energy / count as f64
+            }
+        };
+
+        let threshold = energy_to_lufs(mean(0)) + RELATIVE_GATE;
+        if !threshold.is_finite() {
+            return ::std::f64::NEG_INFINITY;
+        }
+        let from = ((threshold - ABSOLUTE_GATE) * BINS_PER_LU).max(0.0).ceil() as usize;
+        energy_to_lufs(mean(from.min(BINS)))
+    }
+
+    pub fn report(&self) -> LoudnessReport {
+        LoudnessReport {
+            momentary: energy_to_lufs(self.window_energy(MOMENTARY_STEPS)),
+            short_term: energy_to_lufs(self.window_energy(SHORT_TERM_STEPS)),
+            integrated: self.integrated(),
+            true_peak: if self.peak > 0.0 {
+                20.0 * self.peak.log10()
+            } else {
+                ::std::f64::NEG_INFINITY
+            },
+        }
+    }
+}
+
+/// Meters the audio passing a pad when `loudness.target` is set, and judges
+/// the integrated loudness against that target within `loudness.tolerance`
+/// LU (default 1) and the true peak against `loudness.max-true-peak` dBTP
+/// (default -1).
+#[derive(Debug)]
+pub struct Loudness {
+    target: f64,
+    tolerance: f64,
+    max_true_peak: f64,
+    meter: Mutex<LoudnessMeter>,
+}
+
+impl Loudness {
+    pub fn from_config(config: &Config) -> Result<Option<Arc<Loudness>>, Error> {
+        let target = match config.get::<f64>("loudness.target")? {
+            Some(target) => target,
+            None => return Ok(None),
+        };
+
+        Ok(Some(Arc::new(Loudness {
+            target,
+            tolerance: config.get_or("loudness.tolerance", 1.0f64)?,
+            max_true_peak: config.get_or("loudness.max-true-peak", -1.0f64)?,
+            meter: Mutex::new(LoudnessMeter::new()),
+        })))
+    }
+
+    /// Meters every buffer passing `pad`.
+    pub fn attach(loudness: &Arc<Loudness>, pad: &gst::Pad) {
+        let loudness = loudness.clone();
+        pad.add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+            if let Some(gst::PadProbeData::Buffer(ref buffer)) = info.data {
+                if let Some(block) = audio::read_block(pad, buffer) {
+                    loudness
+                        .meter
+                        .lock()
+                        .unwrap()
+                        .push(block.rate, block.channels, &block.samples);
+                }
+            }
+            gst::PadProbeReturn::Ok
+        });
+    }
+
+    pub fn reset(&self) {
+        self.meter.lock().unwrap().reset();
+    }
+}
+
+impl fmt::Display for Loudness {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let report = self.meter.lock().unwrap().report();
+        let mut problems = Vec::new();
+        if !report.integrated.is_finite() {
+            problems.push(String::from("no programme"));
+        } else if (report.integrated - self.target).abs() > self.tolerance {
+            problems.push(format!("{:+.1} LU off target", report.integrated - self.target));
+        }
+        if report.true_peak > self.max_true_peak {
+            problems.push(String::from("true peak over limit"));
+        }
+
+        if problems.is_empty() {
+            write!(f, "{}: on target", report)
+        } else {
+            write!(f, "{}: {}", report, problems.join(", "))
+        }
+    }
+}
+
+/// Adds `loudness reset`, which starts the integrated loudness and true
+/// peak over, and `loudness` to show them.
+pub fn register_commands(control: &mut Control, loudness: &Arc<Loudness>) {
+    let loudness = loudness.clone();
+    control.register("loudness", move |args| match args.first() {
+        Some(&"reset") => {
+            loudness.reset();
+            Ok(loudness.to_string())
+        }
+        None => Ok(loudness.to_string()),
+        Some(other) => Err(Error::from(UnknownCommand(format!("loudness {}", other)))),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+
+    /// Stereo sine of `freq` Hz with its peaks at `dbfs`, starting at
+    /// `phase`, for `seconds`.
+    fn sine(freq: f64, dbfs: f64, phase: f64, seconds: f64) -> Vec<f32> {
+        let amplitude = 10f64.powf(dbfs / 20.0);
+        (0..(seconds * f64::from(RATE)) as usize)
+            .flat_map(|n| {
+                let x = (amplitude * (2.0 * PI * freq * n as f64 / f64::from(RATE) + phase).sin()) as f32;
+                vec![x, x]
+            })
+            .collect()
+    }
+
+    fn measure(parts: &[(f64, f64)]) -> LoudnessReport {
+        let mut meter = LoudnessMeter::new();
+        for &(dbfs, seconds) in parts {
+            meter.push(RATE, 2, &sine(1000.0, dbfs, 0.0, seconds));
+        }
+        meter.report()
+    }
+
+    fn assert_near(value: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (value - expected).abs() <= tolerance,
+            "{} is not within {} of {}",
+            value,
+            tolerance,
+            expected
+        );
+    }
+
+    // The EBU Tech 3341 minimum requirements, with the test signals
+    // generated instead of read from the EBU's files.
+
+    #[test]
+    fn tech_3341_case_1_sine_at_minus_23() {
+        let report = measure(&[(-23.0, 20.0)]);
+        assert_near(report.momentary, -23.0, 0.1);
+        assert_near(report.short_term, -23.0, 0.1);
+        assert_near(report.integrated, -23.0, 0.1);
+    }
+
+    #[test]
+    fn tech_3341_case_2_sine_at_minus_33() {
+        let report = measure(&[(-33.0, 20.0)]);
+        assert_near(report.momentary, -33.0, 0.1);
+        assert_near(report.short_term, -33.0, 0.1);
+        assert_near(report.integrated, -33.0, 0.1);
+    }
+
+    #[test]
+    fn tech_3341_case_3_relative_gate() {
+        let report = measure(&[(-36.0, 10.0), (-23.0, 60.0), (-36.0, 10.0)]);
+        assert_near(report.integrated, -23.0, 0.1);
+    }
+
+    #[test]
+    fn tech_3341_case_4_absolute_and_relative_gate() {
+        let report = measure(&[(-72.0, 10.0), (-36.0, 10.0), (-23.0, 60.0), (-36.0, 10.0), (-72.0, 10.0)]);
+        assert_near(report.integrated, -23.0, 0.1);
+    }
+
+    #[test]
+    fn true_peak_between_samples() {
+        // A quarter of the sample rate 45 degrees out of phase never has a
+        // sample on its peaks, which are 3 dB above the sample peak. Tech
+        // 3341 allows -0.4 to +0.2 dB.
+        let mut meter = LoudnessMeter::new();
+        meter.push(RATE, 2, &sine(f64::from(RATE) / 4.0, 0.0, PI / 4.0, 1.0));
+        let report = meter.report();
+        assert!(
+            report.true_peak > -0.4 && report.true_peak < 0.2,
+            "true peak {} dBTP",
+            report.true_peak
+        );
+    }
+
+    #[test]
+    fn first_phase_passes_samples_through() {
+        let phases = oversampling_phases();
+        for (tap, &h) in phases[0].iter().enumerate() {
+            assert_near(h, if tap == TAPS_PER_PHASE / 2 { 1.0 } else { 0.0 }, 1e-12);
+        }
+    }
+
+    #[test]
+    fn windows_wait_until_full() {
+        let mut meter = LoudnessMeter::new();
+        meter.push(RATE, 2, &sine(1000.0, -23.0, 0.0, 0.3));
+        assert!(!meter.report().momentary.is_finite());
+
+        meter.push(RATE, 2, &sine(1000.0, -23.0, 0.0, 0.1));
+        let report = meter.report();
+        assert_near(report.momentary, -23.0, 0.1);
+        assert!(!report.short_term.is_finite());
+
+        meter.push(RATE, 2, &sine(1000.0, -23.0, 0.0, 2.6));
+        assert_near(meter.report().short_term, -23.0, 0.1);
+    }
+}
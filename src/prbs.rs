@@ -0,0 +1,396 @@
+use gst;
+use gst::prelude::*;
+use gst_app;
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use failure::Error;
+
+use common::{make_bin, make_element};
+use config::{Config, ConfigValueError};
+
+/// Payload type of the PCM test stream.
+pub const PCM_PT: u32 = 97;
+
+const RATE: u64 = 48000;
+const BUFFER_FRAMES: u64 = 480;
+const MASK: u32 = 0x7FFF_FFFF;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PcmFormat {
+    L16,
+    L24,
+}
+
+impl PcmFormat {
+    fn bits(self) -> u32 {
+        match self {
+            PcmFormat::L16 => 16,
+            PcmFormat::L24 => 24,
+        }
+    }
+
+    fn bytes(self) -> usize {
+        self.bits() as usize / 8
+    }
+
+    fn raw_format(self) -> &'static str {
+        match self {
+            PcmFormat::L16 => "S16BE",
+            PcmFormat::L24 => "S24BE",
+        }
+    }
+
+    fn encoding_name(self) -> &'static str {
+        match self {
+            PcmFormat::L16 => "L16",
+            PcmFormat::L24 => "L24",
+        }
+    }
+
+    fn payloader(self) -> &'static str {
+        match self {
+            PcmFormat::L16 => "rtpL16pay",
+            PcmFormat::L24 => "rtpL24pay",
+        }
+    }
+
+    pub fn depayloader(self) -> &'static str {
+        match self {
+            PcmFormat::L16 => "rtpL16depay",
+            PcmFormat::L24 => "rtpL24depay",
+        }
+    }
+}
+
+/// Bit-exact transport test: mono PCM carrying a pseudo-random sequence
+/// instead of Opus audio, enabled on both ends with `pcm.test = l16` or
+/// `l24`. `pcm.seed` picks the sequence on the transmitter.
+#[derive(Debug, Clone, Copy)]
+pub struct PcmTest {
+    pub format: PcmFormat,
+    pub seed: u32,
+}
+
+impl PcmTest {
+    pub fn from_config(config: &Config) -> Result<Option<PcmTest>, Error> {
+        let format = match config.get_str("pcm.test") {
+            None => return Ok(None),
+            Some("l16") => PcmFormat::L16,
+            Some("l24") => PcmFormat::L24,
+            Some(other) => return Err(Error::from(ConfigValueError("pcm.test".into(), other.into()))),
+        };
+        let seed = config.get_or("pcm.seed", 1u32)?;
+
+        Ok(Some(PcmTest { format, seed }))
+    }
+
+    /// Caps of the RTP stream for the receiver's `request-pt-map`.
+    pub fn rtp_caps(&self) -> gst::Caps {
+        gst::Caps::new_simple(
+            "application/x-rtp",
+            &[
+                ("media", &"audio"),
+                ("clock-rate", &(RATE as i32)),
+                ("encoding-name", &self.format.encoding_name()),
+                ("encoding-params", &"1"),
+                ("channels", &1i32),
+            ],
+        )
+    }
+}
+
+/// PRBS-31, x^31 + x^28 + 1. The state is always the last 31 bits
+/// produced, so the receiver can predict the next sample from the ones it
+/// has received and resynchronise by itself after a loss.
+#[derive(Debug, Clone, Copy)]
+pub struct Prbs31(u32);
+
+impl Prbs31 {
+    pub fn new(seed: u32) -> Prbs31 {
+        match seed & MASK {
+            0 => Prbs31(1),
+            state => Prbs31(state),
+        }
+    }
+
+    pub fn next_bits(&mut self, count: u32) -> u32 {
+        let mut value = 0;
+        for _ in 0..count {
+            let bit = ((self.0 >> 30) ^ (self.0 >> 27)) & 1;
+            self.0 = ((self.0 << 1) | bit) & MASK;
+            value = (value << 1) | bit;
+        }
+        value
+    }
+}
+
+/// Sample counts of the PCM test since the receiver started.
+#[derive(Debug, Clone, Default)]
+pub struct PrbsReport {
+    pub verified: u64,
+    pub mismatched: u64,
+    pub concealed: u64,
+    pub missing: u64,
+    pub unverified: u64,
+}
+
+impl fmt::Display for PrbsReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} samples verified, {} mismatched, {} concealed, {} missing, {} unverified while resyncing",
+            self.verified, self.mismatched, self.concealed, self.missing, self.unverified
+        )
+    }
+}
+
+/// Checks received sample codes against the sequence `Prbs31` predicts.
+/// A wrong non-zero sample is a mismatch; zeros are what the pipeline
+/// inserts for lost packets and count as concealed.
+#[derive(Debug)]
+pub struct PrbsChecker {
+    bits: u32,
+    history: u64,
+    known_bits: u32,
+    next_pts: Option<u64>,
+    report: PrbsReport,
+}
+
+impl PrbsChecker {
+    pub fn new(format: PcmFormat) -> PrbsChecker {
+        PrbsChecker {
+            bits: format.bits(),
+            history: 0,
+            known_bits: 0,
+            next_pts: None,
+            report: PrbsReport::default(),
+        }
+    }
+
+    pub fn push(&mut self, codes: &[u32]) {
+        for &code in codes {
+            let expected = if self.known_bits >= 31 {
+                Some(Prbs31((self.history as u32) & MASK).next_bits(self.bits))
+            } else {
+                None
+            };
+
+            let ok = match expected {
+                Some(expected) if expected == code => {
+                    self.report.verified += 1;
+                    true
+                }
+                Some(_) => {
+                    if code == 0 {
+                        self.report.concealed += 1;
+                    } else {
+                        self.report.mismatched += 1;
+                    }
+                    false
+                }
+                None if code == 0 => {
+                    self.report.concealed += 1;
+                    false
+                }
+                None => {
+                    self.report.unverified += 1;
+                    true
+                }
+            };
+
+            // A wrong sample must leave the window before predicting again.
+            self.history = (self.history << self.bits) | code as u64;
+            self.known_bits = if ok { (self.known_bits + self.bits).min(64) } else { 0 };
+        }
+    }
+
+    pub fn push_concealed(&mut self, samples: u64) {
+        self.report.concealed += samples;
+        self.known_bits = 0;
+    }
+
+    pub fn push_missing(&mut self, samples: u64) {
+        self.report.missing += samples;
+        self.known_bits = 0;
+    }
+
+    /// Counts the samples between the end of the previous buffer and `pts`
+    /// as missing.
+    fn check_timestamps(&mut self, pts: Option<u64>, samples: u64) {
+        if let (Some(pts), Some(next_pts)) = (pts, self.next_pts) {
+            let gap = (pts as i64 - next_pts as i64) * RATE as i64;
+            let gap_samples = (gap + 500_000_000) / 1_000_000_000;
+            if gap_samples > 0 {
+                self.push_missing(gap_samples as u64);
+            }
+        }
+        self.next_pts = pts.map(|pts| pts + samples * 1_000_000_000 / RATE);
+    }
+
+    pub fn report(&self) -> PrbsReport {
+        self.report.clone()
+    }
+}
+
+/// Makes the transmitter's source: the test sequence, payloaded with
+/// `PCM_PT`, at the real-time rate the synchronised sink plays it out.
+pub fn make_source(test: &PcmTest) -> Result<gst::Element, Error> {
+    let format = test.format;
+    let caps = gst::Caps::new_simple(
+        "audio/x-raw",
+        &[
+            ("format", &format.raw_format()),
+            ("rate", &(RATE as i32)),
+            ("channels", &1i32),
+            ("layout", &"interleaved"),
+        ],
+    );
+
+    let element = make_element("appsrc", None)?;
+    element.set_property_from_str("format", "time");
+    let appsrc = element
+        .clone()
+        .dynamic_cast::<gst_app::AppSrc>()
+        .expect("Source element is expected to be an appsrc!");
+    appsrc.set_caps(&caps);
+
+    let payloader = make_element(format.payloader(), None)?;
+    payloader.set_property("pt", &PCM_PT.to_value())?;
+
+    let state = Mutex::new((Prbs31::new(test.seed), 0u64));
+    appsrc.set_callbacks(
+        gst_app::AppSrcCallbacks::new()
+            .need_data(move |appsrc, _| {
+                let mut state = state.lock().unwrap();
+                let (ref mut prbs, ref mut frames) = *state;
+
+                let mut data = Vec::with_capacity(BUFFER_FRAMES as usize * format.bytes());
+                for _ in 0..BUFFER_FRAMES {
+                    let code = prbs.next_bits(format.bits());
+                    for i in (0..format.bytes()).rev() {
+                        data.push((code >> (8 * i)) as u8);
+                    }
+                }
+
+                let mut buffer = match gst::Buffer::from_mut_slice(data) {
+                    Some(buffer) => buffer,
+                    None => return,
+                };
+                {
+                    let buffer = buffer.get_mut().unwrap();
+                    buffer.set_pts(gst::ClockTime::from_nseconds(*frames * 1_000_000_000 / RATE));
+                    buffer.set_duration(gst::ClockTime::from_nseconds(BUFFER_FRAMES * 1_000_000_000 / RATE));
+                }
+                *frames += BUFFER_FRAMES;
+                let _ = appsrc.push_buffer(buffer);
+            })
+            .build(),
+    );
+
+    make_bin(&[&element, &payloader])
+}
+
+/// Makes the receiver's sink that checks the depayloaded test sequence.
+pub fn make_checker(test: &PcmTest) -> Result<(gst::Element, Arc<Mutex<PrbsChecker>>), Error> {
+    let format = test.format;
+    let element = make_element("appsink", None)?;
+    element.set_property("sync", &false.to_value())?;
+    let appsink = element
+        .clone()
+        .dynamic_cast::<gst_app::AppSink>()
+        .expect("Sink element is expected to be an appsink!");
+
+    let checker = Arc::new(Mutex::new(PrbsChecker::new(format)));
+    let sample_checker = checker.clone();
+    appsink.set_callbacks(
+        gst_app::AppSinkCallbacks::new()
+            .new_sample(move |appsink| {
+                let sample = match appsink.pull_sample() {
+                    None => return gst::FlowReturn::Eos,
+                    Some(sample) => sample,
+                };
+                let buffer = match sample.get_buffer() {
+                    Some(buffer) => buffer,
+                    None => return gst::FlowReturn::Ok,
+                };
+                let map = match buffer.map_readable() {
+                    Some(map) => map,
+                    None => return gst::FlowReturn::Ok,
+                };
+
+                let mut checker = sample_checker.lock().unwrap();
+                let samples = (map.get_size() / format.bytes()) as u64;
+                checker.check_timestamps(buffer.get_pts().nseconds(), samples);
+
+                if buffer.get_flags().contains(gst::BufferFlags::GAP) {
+                    checker.push_concealed(samples);
+                } else {
+                    let codes = map
+                        .as_slice()
+                        .chunks(format.bytes())
+                        .map(|bytes| bytes.iter().fold(0u32, |acc, &b| (acc << 8) | b as u32))
+                        .collect::<Vec<_>>();
+                    checker.push(&codes);
+                }
+
+                gst::FlowReturn::Ok
+            })
+            .build(),
+    );
+
+    Ok((element, checker))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sequence(format: PcmFormat, seed: u32, samples: usize) -> Vec<u32> {
+        let mut prbs = Prbs31::new(seed);
+        (0..samples).map(|_| prbs.next_bits(format.bits())).collect()
+    }
+
+    #[test]
+    fn synchronises_on_the_first_samples() {
+        // Two samples hold the 31 bits the next one is predicted from.
+        for &format in &[PcmFormat::L16, PcmFormat::L24] {
+            let mut checker = PrbsChecker::new(format);
+            checker.push(&sequence(format, 12345, 1000));
+            let report = checker.report();
+            assert_eq!(report.unverified, 2);
+            assert_eq!(report.verified, 998);
+            assert_eq!(report.mismatched, 0);
+        }
+    }
+
+    #[test]
+    fn counts_errors_and_resynchronises() {
+        let mut codes = sequence(PcmFormat::L16, 12345, 1000);
+        codes[100] ^= 0x0100;
+        codes[500] = 0;
+
+        let mut checker = PrbsChecker::new(PcmFormat::L16);
+        checker.push(&codes);
+        let report = checker.report();
+        assert_eq!(report.mismatched, 1);
+        assert_eq!(report.concealed, 1);
+        // Two samples to sync at the start and after each error.
+        assert_eq!(report.unverified, 6);
+        assert_eq!(report.verified, 1000 - 8);
+    }
+
+    #[test]
+    fn counts_gaps_in_timestamps_as_missing() {
+        let mut checker = PrbsChecker::new(PcmFormat::L16);
+        checker.check_timestamps(Some(0), BUFFER_FRAMES);
+        checker.check_timestamps(Some(20_000_000), BUFFER_FRAMES);
+        assert_eq!(checker.report().missing, BUFFER_FRAMES);
+    }
+
+    #[test]
+    fn zero_seed_still_runs() {
+        assert_ne!(Prbs31::new(0).next_bits(32), 0);
+    }
+}
@@ -0,0 +1,100 @@
+use gst;
+use gst::prelude::*;
+
+use failure::Error;
+
+use common::{get_static_pad, make_element};
+use prbs;
+
+/// Payload type of the Opus stream.
+pub const OPUS_PT: u32 = 96;
+/// Payload type of the ULPFEC packets protecting it.
+pub const FEC_PT: u32 = 100;
+
+#[derive(Debug, Fail)]
+#[fail(display = "Unknown payload type {}", _0)]
+pub struct UnknownPT(pub u32);
+
+#[derive(Debug, Fail)]
+#[fail(display = "No receiver for RTP session {}", _0)]
+pub struct UnknownSession(pub usize);
+
+/// Makes the transmitter's FEC encoder, for rtpbin's `request-fec-encoder`.
+pub fn make_fec_encoder(percentage: u32, percentage_important: u32) -> Result<gst::Element, Error> {
+    let fecenc = make_element("rtpulpfecenc", "fecenc")?;
+
+    fecenc.set_property("pt", &FEC_PT.to_value())?;
+    fecenc.set_property("multipacket", &false.to_value())?;
+//    fecenc.set_property("mux-seq", &true.to_value())?;
+    fecenc.set_property("percentage", &percentage.to_value())?;
+    fecenc.set_property("percentage_important", &percentage_important.to_value())?;
+
+    Ok(fecenc)
+}
+
+/// Makes the receiver's FEC decoder of session `sess_id`, for rtpbin's
+/// `request-fec-decoder`.
+pub fn make_fec_decoder(rtpbin: &gst::Element, sess_id: u32) -> Result<gst::Element, Error> {
+    // The primary keeps the plain name the stats look for.
+    let name = match sess_id {
+        0 => String::from("fecdec"),
+        _ => format!("fecdec{}", sess_id),
+    };
+    let fecdec = make_element("rtpulpfecdec", name.as_str())?;
+    let internal_storage = rtpbin
+        .emit("get-internal-storage", &[&sess_id.to_value()])
+        .unwrap()
+        .unwrap();
+
+    fecdec.set_property("storage", &internal_storage.to_value())?;
+    fecdec.set_property("pt", &FEC_PT.to_value())?;
+    println!("Making fecdec");
+    let recovered = fecdec.get_property("recovered");
+    let unrecovered = fecdec.get_property("unrecovered");
+    println!("{:?}",recovered);
+    println!("{:?}", unrecovered);
+    Ok(fecdec)
+}
+
+/// Caps of the Opus and FEC payload types, for rtpbin's `request-pt-map`.
+pub fn pt_caps(pt: u32) -> Option<gst::Caps> {
+    match pt {
+        FEC_PT => Some(gst::Caps::new_simple(
+            "application/x-rtp",
+            &[
+                ("media", &"audio"),
+                ("clock-rate", &48000i32),
+                ("is-fec", &true),
+            ],
+        )),
+        OPUS_PT => Some(gst::Caps::new_simple(
+            "application/x-rtp",
+            &[
+                ("media", &"audio"),
+                ("clock-rate", &48000i32),
+                ("encoding-name", &"OPUS"),
+            ],
+        )),
+        _ => None,
+    }
+}
+
+/// Links a new rtpbin src pad to the depayloader of its session, the
+/// primary link in session 0 and the backup in session 1.
+pub fn connect_rtpbin_srcpad(rtpbin: &gst::Element, src_pad: &gst::Pad, sinks: &[gst::Element]) -> Result<(), Error> {
+    let name = src_pad.get_name();
+    let split_name = name.split("_");
+    let split_name = split_name.collect::<Vec<&str>>();
+    let session = split_name[3].parse::<usize>()?;
+    let pt = split_name[5].parse::<u32>()?;
+    let sink = sinks.get(session).ok_or_else(|| Error::from(UnknownSession(session)))?;
+    rtpbin.unlink(sink);
+    match pt {
+        OPUS_PT | prbs::PCM_PT => {
+            let sinkpad = get_static_pad(sink, "sink")?;
+            src_pad.link(&sinkpad).into_result()?;
+            Ok(())
+        }
+        _ => Err(Error::from(UnknownPT(pt))),
+    }
+}
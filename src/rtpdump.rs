@@ -0,0 +1,200 @@
+use gst;
+use gst::prelude::*;
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use failure::Error;
+
+#[derive(Debug, Fail)]
+#[fail(display = "Not an rtpdump file: {}", _0)]
+struct NotRtpdump(String);
+
+fn write_u16<W: Write>(w: &mut W, value: u16) -> Result<(), Error> {
+    w.write_all(&[(value >> 8) as u8, value as u8])?;
+    Ok(())
+}
+
+fn write_u32<W: Write>(w: &mut W, value: u32) -> Result<(), Error> {
+    w.write_all(&[(value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8, value as u8])?;
+    Ok(())
+}
+
+/// Writes packets in the rtpdump format of rtptools, which Wireshark and
+/// `rtpplay` read: a text line naming the source, a binary file header,
+/// then every packet with its length and arrival offset in ms.
+pub struct RtpdumpWriter {
+    file: BufWriter<File>,
+    start: SystemTime,
+}
+
+impl RtpdumpWriter {
+    pub fn create(path: &str, source: SocketAddrV4) -> Result<RtpdumpWriter, Error> {
+        RtpdumpWriter::create_at(path, source, SystemTime::now())
+    }
+
+    /// Creates a file whose packet offsets count from `start`.
+    pub fn create_at(path: &str, source: SocketAddrV4, start: SystemTime) -> Result<RtpdumpWriter, Error> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let since_epoch = start.duration_since(UNIX_EPOCH)?;
+
+        writeln!(file, "#!rtpplay1.0 {}/{}", source.ip(), source.port())?;
+        write_u32(&mut file, since_epoch.as_secs() as u32)?;
+        write_u32(&mut file, since_epoch.subsec_micros())?;
+        write_u32(&mut file, u32::from(*source.ip()))?;
+        write_u16(&mut file, source.port())?;
+        file.write_all(&[0, 0])?;
+
+        Ok(RtpdumpWriter { file, start })
+    }
+
+    pub fn write(&mut self, packet: &[u8]) -> Result<(), Error> {
+        self.write_at(packet, SystemTime::now())
+    }
+
+    pub fn write_at(&mut self, packet: &[u8], arrival: SystemTime) -> Result<(), Error> {
+        let offset = arrival
+            .duration_since(self.start)
+            .unwrap_or_else(|_| Duration::from_secs(0));
+        let offset_ms = offset.as_secs() * 1000 + offset.subsec_millis() as u64;
+
+        write_u16(&mut self.file, (packet.len() + 8) as u16)?;
+        write_u16(&mut self.file, packet.len() as u16)?;
+        write_u32(&mut self.file, offset_ms as u32)?;
+        self.file.write_all(packet)?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads the packets of an rtpdump file with their arrival offsets.
+pub struct RtpdumpReader {
+    file: BufReader<File>,
+}
+
+impl RtpdumpReader {
+    pub fn open(path: &str) -> Result<RtpdumpReader, Error> {
+        let mut file = BufReader::new(File::open(path)?);
+
+        let mut line = String::new();
+        file.read_line(&mut line)?;
+        if !line.starts_with("#!rtpplay") {
+            return Err(Error::from(NotRtpdump(path.to_string())));
+        }
+        let mut header = [0u8; 16];
+        file.read_exact(&mut header)?;
+
+        Ok(RtpdumpReader { file })
+    }
+
+    /// Returns the next packet and its offset from the start of the
+    /// capture, or `None` at the end of the file.
+    pub fn next_packet(&mut self) -> Result<Option<(Duration, Vec<u8>)>, Error> {
+        let mut header = [0u8; 8];
+        match self.file.read_exact(&mut header) {
+            Ok(()) => (),
+            Err(ref err) if err.kind() == ::std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(Error::from(err)),
+        }
+
+        let length = (header[0] as usize) << 8 | header[1] as usize;
+        let offset_ms = (header[4] as u64) << 24
+            | (header[5] as u64) << 16
+            | (header[6] as u64) << 8
+            | header[7] as u64;
+
+        let mut packet = vec![0u8; length.saturating_sub(8)];
+        self.file.read_exact(&mut packet)?;
+
+        Ok(Some((Duration::from_millis(offset_ms), packet)))
+    }
+}
+
+/// Writes every buffer passing `pad` to the rtpdump file at `path`. The
+/// probe does not know where packets came from, so the header just names
+/// the link's `port`. Writes are buffered and flushed at EOS; the returned
+/// writer is for flushing on the stats tick in between.
+pub fn attach_capture(pad: &gst::Pad, path: &str, port: i32) -> Result<Arc<Mutex<RtpdumpWriter>>, Error> {
+    let source = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), port as u16);
+    let writer = Arc::new(Mutex::new(RtpdumpWriter::create(path, source)?));
+    eprintln!("Capturing RTP to {}", path);
+
+    let probe_writer = writer.clone();
+    pad.add_probe(gst::PadProbeType::BUFFER | gst::PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+        let result = match info.data {
+            Some(gst::PadProbeData::Buffer(ref buffer)) => match buffer.map_readable() {
+                Some(map) => probe_writer.lock().unwrap().write(map.as_slice()),
+                None => Ok(()),
+            },
+            Some(gst::PadProbeData::Event(ref event)) => match event.view() {
+                gst::EventView::Eos(..) => probe_writer.lock().unwrap().flush(),
+                _ => Ok(()),
+            },
+            _ => Ok(()),
+        };
+        if let Err(err) = result {
+            eprintln!("Failed to write capture: {}", err);
+        }
+        gst::PadProbeReturn::Ok
+    });
+
+    Ok(writer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    fn temp_path(name: &str) -> String {
+        env::temp_dir()
+            .join(format!("tlink-{}-{}.rtpdump", process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = temp_path("round-trip");
+        let start = UNIX_EPOCH + Duration::from_millis(1_500_000_000_250);
+        let packets: Vec<(Duration, Vec<u8>)> = vec![
+            (Duration::from_millis(0), vec![0x80, 96, 0, 1]),
+            (Duration::from_millis(20), vec![0x80, 96, 0, 2, 1, 2, 3]),
+            (Duration::from_millis(70_000), vec![0x80; 1200]),
+        ];
+
+        {
+            let source = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 5004);
+            let mut writer = RtpdumpWriter::create_at(&path, source, start).unwrap();
+            for &(offset, ref packet) in &packets {
+                writer.write_at(packet, start + offset).unwrap();
+            }
+            writer.flush().unwrap();
+        }
+
+        let mut reader = RtpdumpReader::open(&path).unwrap();
+        for expected in &packets {
+            assert_eq!(&reader.next_packet().unwrap().unwrap(), expected);
+        }
+        assert!(reader.next_packet().unwrap().is_none());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let path = temp_path("other");
+        fs::write(&path, b"RIFF....WAVEfmt ").unwrap();
+        assert!(RtpdumpReader::open(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
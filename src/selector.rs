@@ -0,0 +1,275 @@
+use gst;
+use gst::prelude::*;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use failure::Error;
+
+use common::{get_request_pad, get_static_pad, make_bin, make_element, utc_timestamp};
+use config::Config;
+use level::Levels;
+
+/// Steps a crossfade is made of.
+const FADE_STEPS: u32 = 25;
+
+/// Switches the receiver output between sources mixed by an `audiomixer`.
+/// `input-selector` can only cut from one input to another, and it drops
+/// or blocks the buffers of its inactive pads, so the fallback would have
+/// to catch up with the running time at every switch. Instead all inputs
+/// keep running into the mixer and switching crossfades the volumes of its
+/// pads.
+pub struct Selector {
+    mixer: gst::Element,
+    pads: Vec<gst::Pad>,
+    names: Vec<String>,
+    active: usize,
+    fade: Duration,
+    generation: Arc<AtomicUsize>,
+}
+
+impl Selector {
+    pub fn new(mixer: &gst::Element, fade: Duration) -> Selector {
+        Selector {
+            mixer: mixer.clone(),
+            pads: Vec::new(),
+            names: Vec::new(),
+            active: 0,
+            fade,
+            generation: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Links the src pad of `src` to a new mixer input. The first input
+    /// starts on air, the others muted.
+    pub fn add_input(&mut self, name: &str, src: &gst::Element) -> Result<usize, Error> {
+        let srcpad = get_static_pad(src, "src")?;
+        let sinkpad = get_request_pad(&self.mixer, "sink_%u")?;
+        srcpad.link(&sinkpad).into_result()?;
+
+        let volume = if self.pads.is_empty() { 1.0f64 } else { 0.0f64 };
+        sinkpad.set_property("volume", &volume.to_value())?;
+
+        self.pads.push(sinkpad);
+        self.names.push(name.to_string());
+        Ok(self.pads.len() - 1)
+    }
+
+    pub fn inputs(&self) -> usize {
+        self.pads.len()
+    }
+
+    pub fn active(&self) -> usize {
+        self.active
+    }
+
+    pub fn active_name(&self) -> &str {
+        &self.names[self.active]
+    }
+
+    /// Crossfades to input `index`. A fade still running is taken over
+    /// from wherever its volumes got to.
+    pub fn select(&mut self, index: usize) {
+        if index == self.active {
+            return;
+        }
+        self.active = index;
+        eprintln!(
+            "{} On air: {}",
+            utc_timestamp(SystemTime::now()),
+            self.names[index]
+        );
+
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        let current = self.generation.clone();
+        let pads = self.pads.clone();
+        let step = self.fade / FADE_STEPS;
+
+        thread::spawn(move || {
+            let start = pads
+                .iter()
+                .map(|pad| {
+                    pad.get_property("volume")
+                        .ok()
+                        .and_then(|value| value.get::<f64>())
+                        .unwrap_or(0.0)
+                })
+                .collect::<Vec<_>>();
+
+            for i in 1..FADE_STEPS + 1 {
+                if current.load(Ordering::SeqCst) != generation {
+                    return;
+                }
+                let position = i as f64 / FADE_STEPS as f64;
+                for (n, pad) in pads.iter().enumerate() {
+                    let target = if n == index { 1.0 } else { 0.0 };
+                    let volume = start[n] + (target - start[n]) * position;
+                    let _ = pad.set_property("volume", &volume.to_value());
+                }
+                thread::sleep(step);
+            }
+        });
+    }
+}
+
+/// Whether a link is delivering programme: packets arrived within
+/// `fallback.timeout` ms (default 1000), and unless `fallback.silence` is 0
+/// its audio has not stayed below `silence.threshold` dBFS for that many
+/// seconds (default 5).
+#[derive(Debug)]
+pub struct LinkHealth {
+    timeout: Duration,
+    silence: Option<Duration>,
+    threshold_db: f64,
+    last_packet: Mutex<Option<Instant>>,
+    last_sound: Mutex<Instant>,
+}
+
+impl LinkHealth {
+    pub fn from_config(config: &Config) -> Result<Arc<LinkHealth>, Error> {
+        let silence = config.get_or("fallback.silence", 5u64)?;
+        Ok(Arc::new(LinkHealth {
+            timeout: Duration::from_millis(config.get_or("fallback.timeout", 1000u64)?),
+            silence: if silence == 0 { None } else { Some(Duration::from_secs(silence)) },
+            threshold_db: config.get_or("silence.threshold", -50.0f64)?,
+            last_packet: Mutex::new(None),
+            last_sound: Mutex::new(Instant::now()),
+        }))
+    }
+
+    /// Notes the arrival of every packet passing `pad`.
+    pub fn attach(health: &Arc<LinkHealth>, pad: &gst::Pad) {
+        let health = health.clone();
+        pad.add_probe(gst::PadProbeType::BUFFER, move |_, _| {
+            *health.last_packet.lock().unwrap() = Some(Instant::now());
+            gst::PadProbeReturn::Ok
+        });
+    }
+
+    /// Feeds the levels of the link's decoded audio.
+    pub fn update_levels(&self, levels: &Levels) {
+        let loudest = levels.rms.iter().cloned().fold(::std::f64::NEG_INFINITY, f64::max);
+        if loudest >= self.threshold_db {
+            *self.last_sound.lock().unwrap() = Instant::now();
+        }
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        let receiving = self
+            .last_packet
+            .lock()
+            .unwrap()
+            .map(|last| last.elapsed() < self.timeout)
+            .unwrap_or(false);
+        let sounding = match self.silence {
+            Some(silence) => self.last_sound.lock().unwrap().elapsed() < silence,
+            None => true,
+        };
+        receiving && sounding
+    }
+}
+
+/// Picks the input to put on air from inputs in order of preference. An
+/// unhealthy input is left at once for the next healthy one; a preferred
+/// input is only returned to once it has been healthy for `stable`.
+#[derive(Debug)]
+pub struct Failover {
+    stable: Duration,
+    healthy_since: Vec<Option<Instant>>,
+}
+
+impl Failover {
+    pub fn new(inputs: usize, stable: Duration) -> Failover {
+        Failover {
+            stable,
+            healthy_since: vec![None; inputs],
+        }
+    }
+
+    pub fn choose(&mut self, healthy: &[bool], active: usize) -> usize {
+        self.choose_at(healthy, active, Instant::now())
+    }
+
+    /// Chooses as if the time were `now`.
+    pub fn choose_at(&mut self, healthy: &[bool], active: usize, now: Instant) -> usize {
+        for (since, &healthy) in self.healthy_since.iter_mut().zip(healthy) {
+            *since = match (*since, healthy) {
+                (_, false) => None,
+                (None, true) => Some(now),
+                (since, true) => since,
+            };
+        }
+
+        for (i, since) in self.healthy_since.iter().enumerate() {
+            let since = match *since {
+                Some(since) => since,
+                None => continue,
+            };
+            if i >= active || now.duration_since(since) >= self.stable {
+                return i;
+            }
+        }
+        active
+    }
+}
+
+/// Converts an input's audio to the format all mixer inputs share.
+pub fn make_input_chain() -> Result<gst::Element, Error> {
+    let audioconvert = make_element("audioconvert", None)?;
+    let audioresample = make_element("audioresample", None)?;
+    let capsfilter = make_element("capsfilter", None)?;
+    let caps = gst::Caps::new_simple(
+        "audio/x-raw",
+        &[("format", &"F32LE"), ("rate", &48000i32), ("channels", &2i32)],
+    );
+    capsfilter.set_property("caps", &caps.to_value())?;
+
+    make_bin(&[&audioconvert, &audioresample, &capsfilter])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STABLE: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn leaves_an_unhealthy_input_at_once() {
+        let start = Instant::now();
+        let mut failover = Failover::new(3, STABLE);
+        assert_eq!(failover.choose_at(&[true, true, true], 0, start), 0);
+        assert_eq!(failover.choose_at(&[false, true, true], 0, start), 1);
+        assert_eq!(failover.choose_at(&[false, false, true], 1, start), 2);
+    }
+
+    #[test]
+    fn returns_after_the_hold_down() {
+        let start = Instant::now();
+        let mut failover = Failover::new(3, STABLE);
+        assert_eq!(failover.choose_at(&[false, true, true], 0, start), 1);
+
+        let back = start + Duration::from_secs(1);
+        assert_eq!(failover.choose_at(&[true, true, true], 1, back), 1);
+        assert_eq!(failover.choose_at(&[true, true, true], 1, back + STABLE / 2), 1);
+        assert_eq!(failover.choose_at(&[true, true, true], 1, back + STABLE), 0);
+    }
+
+    #[test]
+    fn flapping_restarts_the_hold_down() {
+        let start = Instant::now();
+        let mut failover = Failover::new(2, STABLE);
+        assert_eq!(failover.choose_at(&[false, true], 0, start), 1);
+        assert_eq!(failover.choose_at(&[true, true], 1, start + STABLE / 2), 1);
+        assert_eq!(failover.choose_at(&[false, true], 1, start + STABLE), 1);
+        assert_eq!(failover.choose_at(&[true, true], 1, start + STABLE * 2), 1);
+        assert_eq!(failover.choose_at(&[true, true], 1, start + STABLE * 3), 0);
+    }
+
+    #[test]
+    fn stays_when_nothing_is_healthy() {
+        let mut failover = Failover::new(2, STABLE);
+        assert_eq!(failover.choose_at(&[false, false], 1, Instant::now()), 1);
+    }
+}
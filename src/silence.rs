@@ -0,0 +1,97 @@
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use failure::Error;
+
+use common::utc_timestamp;
+use config::Config;
+use level::Levels;
+
+/// Raises an alarm when the programme has stayed below `silence.threshold`
+/// dBFS rms (default -50) for `silence.duration` seconds (default 10), and
+/// clears it when sound returns. A `silence.duration` of 0 turns it off.
+///
+/// Both events are logged and passed to the `silence.hook` script, if set,
+/// as `HOOK silence SECONDS` and `HOOK clear SECONDS`, where SECONDS is how
+/// long the programme had been silent.
+#[derive(Debug)]
+pub struct SilenceDetector {
+    threshold_db: f64,
+    duration: Duration,
+    hook: Option<String>,
+    last_sound: Instant,
+    alarm: bool,
+    alarms: usize,
+}
+
+impl SilenceDetector {
+    pub fn from_config(config: &Config) -> Result<Option<SilenceDetector>, Error> {
+        let duration = config.get_or("silence.duration", 10u64)?;
+        if duration == 0 {
+            return Ok(None);
+        }
+
+        Ok(Some(SilenceDetector {
+            threshold_db: config.get_or("silence.threshold", -50.0f64)?,
+            duration: Duration::from_secs(duration),
+            hook: config.get_str("silence.hook").map(String::from),
+            last_sound: Instant::now(),
+            alarm: false,
+            alarms: 0,
+        }))
+    }
+
+    /// Feeds the levels of the decoded audio.
+    pub fn update(&mut self, levels: &Levels) {
+        let loudest = levels.rms.iter().cloned().fold(::std::f64::NEG_INFINITY, f64::max);
+        if loudest < self.threshold_db {
+            return;
+        }
+
+        if self.alarm {
+            self.alarm = false;
+            let silent_for = self.last_sound.elapsed();
+            self.raise("clear", silent_for);
+        }
+        self.last_sound = Instant::now();
+    }
+
+    /// Must be called periodically: when the link is down no levels arrive
+    /// at all, and that is silence too.
+    pub fn check(&mut self) {
+        let silent_for = self.last_sound.elapsed();
+        if !self.alarm && silent_for >= self.duration {
+            self.alarm = true;
+            self.alarms += 1;
+            self.raise("silence", silent_for);
+        }
+    }
+
+    pub fn is_silent(&self) -> bool {
+        self.alarm
+    }
+
+    pub fn alarms(&self) -> usize {
+        self.alarms
+    }
+
+    fn raise(&self, event: &str, silent_for: Duration) {
+        let seconds = silent_for.as_secs().to_string();
+        eprintln!(
+            "{} Silence alarm: {} after {} s",
+            utc_timestamp(SystemTime::now()),
+            event,
+            seconds
+        );
+
+        if let Some(ref hook) = self.hook {
+            match Command::new(hook).arg(event).arg(&seconds).spawn() {
+                Ok(mut child) => {
+                    thread::spawn(move || child.wait());
+                }
+                Err(err) => eprintln!("Failed to run silence hook {}: {}", hook, err),
+            }
+        }
+    }
+}
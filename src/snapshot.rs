@@ -0,0 +1,260 @@
+use gst;
+use gst::prelude::*;
+
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::Write;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use failure::Error;
+
+use audio;
+use common::{gap_span, get_static_pad, utc_timestamp};
+use config::Config;
+use rtpdump::RtpdumpWriter;
+
+#[derive(Debug, Default)]
+struct AudioRing {
+    rate: u32,
+    channels: usize,
+    samples: VecDeque<f32>,
+}
+
+/// A snapshot waiting for `snapshot.post` to pass.
+#[derive(Debug)]
+struct Pending {
+    deadline: Instant,
+    reason: String,
+    /// The link stats of the first check after the trigger.
+    stats: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct Trigger {
+    history: VecDeque<(Instant, u64)>,
+    /// The lost span the decoder is concealing, as `(start, end)` in ns,
+    /// and whether it has triggered a snapshot already.
+    concealing: Option<(u64, u64, bool)>,
+    pending: Option<Pending>,
+    holdoff_until: Option<Instant>,
+}
+
+/// Keeps the last `snapshot.seconds` of received packets and decoded audio
+/// and saves them to `snapshot.dir` when more than `snapshot.threshold`
+/// packets go unrecovered within `snapshot.window` ms, or when the decoder
+/// conceals more than `snapshot.conceal` ms (default 200) of lost audio in
+/// one go. The files are saved `snapshot.post` seconds after the loss so
+/// they show what followed it: `incident-<UTC time>.rtpdump`, `.wav` unless
+/// no audio was recorded, and `.txt` with the link stats as they were when
+/// the loss was noticed.
+#[derive(Debug)]
+pub struct Snapshot {
+    dir: String,
+    port: i32,
+    length: Duration,
+    window: Duration,
+    post: Duration,
+    threshold: u64,
+    conceal_ns: u64,
+    packets: Mutex<VecDeque<(SystemTime, Vec<u8>)>>,
+    audio: Mutex<AudioRing>,
+    trigger: Mutex<Trigger>,
+}
+
+impl Snapshot {
+    pub fn from_config(config: &Config, port: i32) -> Result<Option<Arc<Snapshot>>, Error> {
+        let dir = match config.get_str("snapshot.dir") {
+            Some(dir) => dir.to_string(),
+            None => return Ok(None),
+        };
+        fs::create_dir_all(&dir)?;
+
+        Ok(Some(Arc::new(Snapshot {
+            dir,
+            port,
+            length: Duration::from_secs(config.get_or("snapshot.seconds", 10u64)?),
+            window: Duration::from_millis(config.get_or("snapshot.window", 1000u64)?),
+            post: Duration::from_secs(config.get_or("snapshot.post", 2u64)?),
+            threshold: config.get_or("snapshot.threshold", 5u64)?,
+            conceal_ns: config.get_or("snapshot.conceal", 200u64)? * 1_000_000,
+            packets: Mutex::new(VecDeque::new()),
+            audio: Mutex::new(AudioRing::default()),
+            trigger: Mutex::new(Trigger::default()),
+        })))
+    }
+
+    fn push_packet(&self, packet: &[u8]) {
+        let now = SystemTime::now();
+        let mut packets = self.packets.lock().unwrap();
+        packets.push_back((now, packet.to_vec()));
+        while let Some(&(arrival, _)) = packets.front() {
+            match now.duration_since(arrival) {
+                Ok(age) if age > self.length => {
+                    packets.pop_front();
+                }
+                _ => break,
+            }
+        }
+    }
+
+    fn push_audio(&self, block: &audio::AudioBlock) {
+        let mut ring = self.audio.lock().unwrap();
+        if ring.rate != block.rate || ring.channels != block.channels {
+            ring.rate = block.rate;
+            ring.channels = block.channels;
+            ring.samples.clear();
+        }
+
+        ring.samples.extend(block.samples.iter().cloned());
+        let max_len = self.length.as_secs() as usize * block.rate as usize * block.channels;
+        while ring.samples.len() > max_len {
+            ring.samples.pop_front();
+        }
+    }
+
+    /// Schedules a snapshot for `reason` unless one is pending or was
+    /// taken within the last `snapshot.seconds`.
+    fn arm(&self, trigger: &mut Trigger, now: Instant, reason: String) {
+        let held_off = trigger.holdoff_until.map(|until| now < until).unwrap_or(false);
+        if trigger.pending.is_none() && !held_off {
+            trigger.pending = Some(Pending {
+                deadline: now + self.post,
+                reason,
+                stats: None,
+            });
+            trigger.holdoff_until = Some(now + self.length);
+        }
+    }
+
+    /// Follows the lost packets the decoder conceals, joining adjacent ones
+    /// into a single concealment burst.
+    fn lost(&self, start: u64, duration: u64) {
+        let mut trigger = self.trigger.lock().unwrap();
+        let (burst_start, triggered) = match trigger.concealing {
+            Some((burst_start, end, triggered)) if start <= end + 1_000_000 => (burst_start, triggered),
+            _ => (start, false),
+        };
+        let end = start + duration;
+        let burst_ns = end.saturating_sub(burst_start);
+        let trigger_now = !triggered && self.conceal_ns > 0 && burst_ns >= self.conceal_ns;
+        trigger.concealing = Some((burst_start, end, triggered || trigger_now));
+        if trigger_now {
+            let reason = format!("{} ms of audio concealed in one burst", burst_ns / 1_000_000);
+            self.arm(&mut trigger, Instant::now(), reason);
+        }
+    }
+
+    /// Called periodically with the total number of packets the receiver
+    /// could not recover and a description of the current link stats. The
+    /// stats of the check that notices a loss, or of the first one after a
+    /// concealment burst, are the ones saved with the snapshot.
+    pub fn check(&self, unrecovered: u64, stats: &str) {
+        let now = Instant::now();
+        let mut trigger = self.trigger.lock().unwrap();
+
+        trigger.history.push_back((now, unrecovered));
+        while trigger.history.len() > 1 && now.duration_since(trigger.history[0].0) > self.window {
+            trigger.history.pop_front();
+        }
+
+        let burst = unrecovered.saturating_sub(trigger.history[0].1);
+        if burst >= self.threshold {
+            let reason = format!("{} packets unrecovered within {:?}", burst, self.window);
+            self.arm(&mut trigger, now, reason);
+        }
+
+        let due = match trigger.pending {
+            Some(ref mut pending) => {
+                if pending.stats.is_none() {
+                    pending.stats = Some(stats.to_string());
+                }
+                now >= pending.deadline
+            }
+            None => false,
+        };
+        if due {
+            let pending = trigger.pending.take().expect("due without a pending snapshot");
+            drop(trigger);
+            match self.save(&pending.reason, pending.stats.as_ref().map(String::as_str).unwrap_or(stats)) {
+                Ok(name) => eprintln!("Saved incident snapshot {}", name),
+                Err(err) => eprintln!("Failed to save incident snapshot: {}", err),
+            }
+        }
+    }
+
+    fn save(&self, reason: &str, stats: &str) -> Result<String, Error> {
+        let name = format!("incident-{}", utc_timestamp(SystemTime::now()));
+        let base = Path::new(&self.dir).join(&name);
+        let base = base.to_string_lossy();
+
+        // Copy the rings out first, so that the streaming threads feeding
+        // them do not wait for the disk.
+        let packets = self.packets.lock().unwrap().iter().cloned().collect::<Vec<_>>();
+        let (rate, channels, samples) = {
+            let ring = self.audio.lock().unwrap();
+            (ring.rate, ring.channels, ring.samples.iter().cloned().collect::<Vec<_>>())
+        };
+
+        let start = packets.first().map(|&(arrival, _)| arrival).unwrap_or_else(SystemTime::now);
+        let source = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), self.port as u16);
+        let mut writer = RtpdumpWriter::create_at(&format!("{}.rtpdump", base), source, start)?;
+        for &(arrival, ref packet) in &packets {
+            writer.write_at(packet, arrival)?;
+        }
+        writer.flush()?;
+
+        if rate > 0 {
+            audio::write_wav(&format!("{}.wav", base), rate, channels, &samples)?;
+        }
+
+        let mut file = File::create(format!("{}.txt", base))?;
+        writeln!(file, "{}", reason)?;
+        writeln!(file, "{}", stats)?;
+
+        Ok(name)
+    }
+}
+
+/// Records the packets passing `pad` into the snapshot.
+pub fn attach_packets(snapshot: &Arc<Snapshot>, pad: &gst::Pad) {
+    let snapshot = snapshot.clone();
+    pad.add_probe(gst::PadProbeType::BUFFER, move |_, info| {
+        if let Some(gst::PadProbeData::Buffer(ref buffer)) = info.data {
+            if let Some(map) = buffer.map_readable() {
+                snapshot.push_packet(map.as_slice());
+            }
+        }
+        gst::PadProbeReturn::Ok
+    });
+}
+
+/// Records the raw audio passing `pad` into the snapshot.
+pub fn attach_audio(snapshot: &Arc<Snapshot>, pad: &gst::Pad) {
+    let snapshot = snapshot.clone();
+    pad.add_probe(gst::PadProbeType::BUFFER, move |pad, info| {
+        if let Some(gst::PadProbeData::Buffer(ref buffer)) = info.data {
+            if let Some(block) = audio::read_block(pad, buffer) {
+                snapshot.push_audio(&block);
+            }
+        }
+        gst::PadProbeReturn::Ok
+    });
+}
+
+/// Watches the gaps the depayloader sends `decoder` for lost packets, for
+/// concealment bursts.
+pub fn attach_concealment(snapshot: &Arc<Snapshot>, decoder: &gst::Element) -> Result<(), Error> {
+    let snapshot = snapshot.clone();
+    get_static_pad(decoder, "sink")?.add_probe(gst::PadProbeType::EVENT_DOWNSTREAM, move |_, info| {
+        if let Some(gst::PadProbeData::Event(ref event)) = info.data {
+            if let Some((start, duration)) = gap_span(event) {
+                snapshot.lost(start, duration);
+            }
+        }
+        gst::PadProbeReturn::Ok
+    });
+    Ok(())
+}
@@ -0,0 +1,302 @@
+use glib;
+use gst;
+use gst::prelude::*;
+
+use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+
+use failure::Error;
+
+use common::{get_static_pad, make_element};
+use config::Config;
+
+#[derive(Debug, Fail)]
+#[fail(display = "Invalid SRTP key: {}", _0)]
+pub struct InvalidKey(String);
+
+#[derive(Debug, Fail)]
+#[fail(display = "Unsupported SRTP cipher {}", _0)]
+pub struct UnsupportedCipher(String);
+
+/// How often the key file is checked for a rotated key.
+const KEY_FILE_POLL_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
+/// How long packets may keep failing authentication before it is reported.
+const AUTH_CHECK_INTERVAL: time::Duration = time::Duration::from_secs(2);
+
+/// Pre-shared SRTP master key (key followed by salt) and the crypto suite it
+/// is used with.
+#[derive(Debug, Clone)]
+pub struct SrtpKey {
+    pub cipher: String,
+    pub auth: String,
+    pub key: Vec<u8>,
+}
+
+/// SRTP settings from the `srtp.*` config keys. Either `srtp.key` or
+/// `srtp.key-file` holds the master key as hex; a key file is watched and
+/// re-read when it changes so the key can be rotated without a restart.
+#[derive(Debug, Clone)]
+pub struct SrtpSettings {
+    pub key: SrtpKey,
+    pub key_file: Option<String>,
+}
+
+fn master_key_len(cipher: &str) -> Result<usize, Error> {
+    match cipher {
+        "aes-128-icm" => Ok(30),
+        "aes-256-icm" => Ok(46),
+        "aes-128-gcm" => Ok(28),
+        "aes-256-gcm" => Ok(44),
+        _ => Err(Error::from(UnsupportedCipher(cipher.to_string()))),
+    }
+}
+
+fn parse_hex(text: &str) -> Result<Vec<u8>, Error> {
+    let digits = text
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<Vec<char>>();
+    if digits.len() % 2 != 0 {
+        return Err(Error::from(InvalidKey("odd number of hex digits".into())));
+    }
+
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let byte = pair.iter().collect::<String>();
+            u8::from_str_radix(&byte, 16)
+                .map_err(|_| Error::from(InvalidKey(format!("{} is not a hex byte", byte))))
+        })
+        .collect()
+}
+
+impl SrtpKey {
+    fn parse(cipher: &str, auth: &str, hex: &str) -> Result<SrtpKey, Error> {
+        let key = parse_hex(hex)?;
+        let expected = master_key_len(cipher)?;
+        if key.len() != expected {
+            return Err(Error::from(InvalidKey(format!(
+                "{} needs {} bytes of key and salt, got {}",
+                cipher,
+                expected,
+                key.len()
+            ))));
+        }
+
+        Ok(SrtpKey {
+            cipher: cipher.to_string(),
+            auth: auth.to_string(),
+            key,
+        })
+    }
+
+    fn load(cipher: &str, auth: &str, path: &str) -> Result<SrtpKey, Error> {
+        SrtpKey::parse(cipher, auth, &fs::read_to_string(path)?)
+    }
+
+    pub fn to_buffer(&self) -> Result<gst::Buffer, Error> {
+        gst::Buffer::from_slice(self.key.clone())
+            .ok_or_else(|| Error::from(InvalidKey("could not allocate key buffer".into())))
+    }
+
+    /// Caps in the form srtpdec expects as the answer to its key requests.
+    pub fn to_caps(&self) -> Result<gst::Caps, Error> {
+        let buffer = self.to_buffer()?;
+        Ok(gst::Caps::new_simple(
+            "application/x-srtp",
+            &[
+                ("srtp-key", &buffer),
+                ("srtp-cipher", &self.cipher.as_str()),
+                ("srtp-auth", &self.auth.as_str()),
+                ("srtcp-cipher", &self.cipher.as_str()),
+                ("srtcp-auth", &self.auth.as_str()),
+            ],
+        ))
+    }
+}
+
+impl SrtpSettings {
+    /// Returns `None` when no key is configured and the link runs in clear RTP.
+    pub fn from_config(config: &Config) -> Result<Option<SrtpSettings>, Error> {
+        let cipher = config.get_str("srtp.cipher").unwrap_or("aes-128-icm");
+        let auth = config.get_str("srtp.auth").unwrap_or("hmac-sha1-80");
+
+        if let Some(path) = config.get_str("srtp.key-file") {
+            return Ok(Some(SrtpSettings {
+                key: SrtpKey::load(cipher, auth, path)?,
+                key_file: Some(path.to_string()),
+            }));
+        }
+
+        match config.get_str("srtp.key") {
+            Some(hex) => Ok(Some(SrtpSettings {
+                key: SrtpKey::parse(cipher, auth, hex)?,
+                key_file: None,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    /// Calls `on_change` with the new key whenever the key file is modified.
+    /// Does nothing when the key was given inline in the config.
+    pub fn watch_key_file<F>(&self, on_change: F)
+    where
+        F: Fn(SrtpKey) + Send + 'static,
+    {
+        let path = match self.key_file {
+            Some(ref path) => path.clone(),
+            None => return,
+        };
+        let cipher = self.key.cipher.clone();
+        let auth = self.key.auth.clone();
+
+        thread::spawn(move || {
+            let modified = |path: &str| fs::metadata(path).and_then(|m| m.modified()).ok();
+            let mut last_modified = modified(&path);
+
+            loop {
+                thread::sleep(KEY_FILE_POLL_INTERVAL);
+                let current = modified(&path);
+                if current == last_modified {
+                    continue;
+                }
+                last_modified = current;
+
+                match SrtpKey::load(&cipher, &auth, &path) {
+                    Ok(key) => {
+                        println!("Rotating SRTP key from {}", path);
+                        on_change(key);
+                    }
+                    Err(err) => eprintln!("Keeping old SRTP key, {} is not usable: {}", path, err),
+                }
+            }
+        });
+    }
+}
+
+pub fn make_encoder(key: &SrtpKey) -> Result<gst::Element, Error> {
+    let srtpenc = make_element("srtpenc", "srtpenc")?;
+
+    srtpenc.set_property_from_str("rtp-cipher", &key.cipher);
+    srtpenc.set_property_from_str("rtp-auth", &key.auth);
+    srtpenc.set_property_from_str("rtcp-cipher", &key.cipher);
+    srtpenc.set_property_from_str("rtcp-auth", &key.auth);
+    set_encoder_key(&srtpenc, key)?;
+
+    Ok(srtpenc)
+}
+
+/// srtpenc recreates its streams with the new key on the next packet.
+pub fn set_encoder_key(srtpenc: &gst::Element, key: &SrtpKey) -> Result<(), Error> {
+    srtpenc.set_property("key", &key.to_buffer()?.to_value())?;
+    Ok(())
+}
+
+/// Counts packets entering and leaving srtpdec. srtpdec silently drops
+/// packets that fail authentication, so a wrong key otherwise only shows up
+/// as a dead link. Each RTP session needs its own monitor, or a link with
+/// the wrong key hides behind one that decrypts fine.
+#[derive(Debug, Clone, Default)]
+pub struct AuthMonitor {
+    link: String,
+    received: Arc<AtomicUsize>,
+    decrypted: Arc<AtomicUsize>,
+}
+
+impl AuthMonitor {
+    /// A monitor for the link named `link` in its messages.
+    pub fn new(link: &str) -> AuthMonitor {
+        AuthMonitor {
+            link: link.to_string(),
+            ..AuthMonitor::default()
+        }
+    }
+
+    pub fn spawn(&self) {
+        let monitor = self.clone();
+        thread::spawn(move || {
+            let mut last_received = 0;
+            let mut last_decrypted = 0;
+            let mut failing = false;
+
+            loop {
+                thread::sleep(AUTH_CHECK_INTERVAL);
+                let received = monitor.received.load(Ordering::Relaxed);
+                let decrypted = monitor.decrypted.load(Ordering::Relaxed);
+
+                if received > last_received && decrypted == last_decrypted {
+                    eprintln!(
+                        "SRTP authentication failed for {} packets on the {} link: \
+                         check that both ends use the same key and cipher",
+                        received - last_received,
+                        monitor.link
+                    );
+                    failing = true;
+                } else if failing && decrypted > last_decrypted {
+                    eprintln!("SRTP authentication recovered on the {} link", monitor.link);
+                    failing = false;
+                }
+
+                last_received = received;
+                last_decrypted = decrypted;
+            }
+        });
+    }
+
+    fn count(&self, srtpdec: &gst::Element) -> Result<(), Error> {
+        let received = self.received.clone();
+        get_static_pad(srtpdec, "rtp_sink")?.add_probe(gst::PadProbeType::BUFFER, move |_, _| {
+            received.fetch_add(1, Ordering::Relaxed);
+            gst::PadProbeReturn::Ok
+        });
+
+        let decrypted = self.decrypted.clone();
+        get_static_pad(srtpdec, "rtp_src")?.add_probe(gst::PadProbeType::BUFFER, move |_, _| {
+            decrypted.fetch_add(1, Ordering::Relaxed);
+            gst::PadProbeReturn::Ok
+        });
+
+        Ok(())
+    }
+}
+
+fn key_caps(key: &Arc<Mutex<SrtpKey>>) -> Option<glib::Value> {
+    match key.lock().unwrap().to_caps() {
+        Ok(caps) => Some(caps.to_value()),
+        Err(err) => {
+            eprintln!("Could not make SRTP key caps: {}", err);
+            None
+        }
+    }
+}
+
+/// Name of the srtpdec of RTP session `session`.
+pub fn decoder_name(session: u32) -> String {
+    match session {
+        0 => String::from("srtpdec"),
+        _ => format!("srtpdec{}", session),
+    }
+}
+
+pub fn make_decoder(key: &Arc<Mutex<SrtpKey>>, monitor: &AuthMonitor, session: u32) -> Result<gst::Element, Error> {
+    let srtpdec = make_element("srtpdec", decoder_name(session).as_str())?;
+
+    // The same key answers the initial request and the rollover limits; a
+    // rotated key is picked up after "clear-keys".
+    for signal in &["request-key", "soft-limit", "hard-limit"] {
+        let key = key.clone();
+        srtpdec.connect(*signal, false, move |_| key_caps(&key))?;
+    }
+    monitor.count(&srtpdec)?;
+
+    Ok(srtpdec)
+}
+
+/// Makes srtpdec forget its current key and ask for the rotated one.
+pub fn clear_decoder_keys(srtpdec: &gst::Element) {
+    let _ = srtpdec.emit("clear-keys", &[]);
+}
@@ -0,0 +1,216 @@
+use std::f64::consts::PI;
+use std::fmt;
+
+use audio::power_to_db;
+
+/// Length of the blocks the signal is analysed in. 50 ms gives Goertzel a
+/// 20 Hz wide bin while still catching short glitches.
+const BLOCK_SECONDS: f64 = 0.05;
+
+/// Blocks quieter than this are counted as a dropout.
+const DROPOUT_DB: f64 = -50.0;
+
+/// Blocks where the tone carries less than this share of the power are
+/// counted as a glitch: clicks, concealment artifacts or noise.
+const MIN_PURITY: f64 = 0.9;
+
+/// What the analyzer saw since the previous report.
+#[derive(Debug, Clone, Default)]
+pub struct ToneReport {
+    pub blocks: usize,
+    pub level_db: f64,
+    pub freq_error_hz: f64,
+    pub max_freq_error_hz: f64,
+    pub dropouts: usize,
+    pub glitches: usize,
+    pub total_dropouts: usize,
+    pub total_glitches: usize,
+}
+
+impl ToneReport {
+    /// The link is clean when the tone was there all the time, undistorted
+    /// and within `tolerance_hz` of the expected frequency.
+    pub fn is_clean(&self, tolerance_hz: f64) -> bool {
+        self.blocks > 0
+            && self.dropouts == 0
+            && self.glitches == 0
+            && self.max_freq_error_hz.abs() <= tolerance_hz
+    }
+}
+
+impl fmt::Display for ToneReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "level {:.1} dBFS, frequency error {:+.2} Hz (max {:+.2}), \
+             dropouts {} ({} total), glitches {} ({} total)",
+            self.level_db,
+            self.freq_error_hz,
+            self.max_freq_error_hz,
+            self.dropouts,
+            self.total_dropouts,
+            self.glitches,
+            self.total_glitches
+        )
+    }
+}
+
+/// Checks that a received signal is the test tone `transmitter_audiotestsrc`
+/// sends at FREQ: the tone's share of the power is measured with Goertzel
+/// and its frequency from interpolated zero crossings.
+#[derive(Debug)]
+pub struct ToneAnalyzer {
+    freq: f64,
+    rate: u32,
+    block: Vec<f32>,
+    in_dropout: bool,
+    silent_blocks: usize,
+    report: ToneReport,
+    level_sum: f64,
+    freq_error_sum: f64,
+    freq_blocks: usize,
+}
+
+impl ToneAnalyzer {
+    pub fn new(freq: f64) -> ToneAnalyzer {
+        ToneAnalyzer {
+            freq,
+            rate: 0,
+            block: Vec::new(),
+            in_dropout: false,
+            silent_blocks: 0,
+            report: ToneReport::default(),
+            level_sum: 0.0,
+            freq_error_sum: 0.0,
+            freq_blocks: 0,
+        }
+    }
+
+    /// Feeds mono samples at `rate`.
+    pub fn push(&mut self, rate: u32, samples: &[f32]) {
+        if rate != self.rate {
+            self.rate = rate;
+            self.block.clear();
+        }
+
+        let block_len = (rate as f64 * BLOCK_SECONDS) as usize;
+        for &sample in samples {
+            self.block.push(sample);
+            if self.block.len() == block_len {
+                self.analyze_block();
+                self.block.clear();
+            }
+        }
+    }
+
+    /// Returns the report for the time since the last call.
+    pub fn take_report(&mut self) -> ToneReport {
+        let mut report = self.report.clone();
+        let tone_blocks = report.blocks - self.silent_blocks;
+        if tone_blocks > 0 {
+            report.level_db = self.level_sum / tone_blocks as f64;
+        }
+        if self.freq_blocks > 0 {
+            report.freq_error_hz = self.freq_error_sum / self.freq_blocks as f64;
+        }
+
+        self.report = ToneReport {
+            total_dropouts: report.total_dropouts,
+            total_glitches: report.total_glitches,
+            ..ToneReport::default()
+        };
+        self.level_sum = 0.0;
+        self.freq_error_sum = 0.0;
+        self.freq_blocks = 0;
+        self.silent_blocks = 0;
+
+        report
+    }
+
+    fn analyze_block(&mut self) {
+        let n = self.block.len();
+        self.report.blocks += 1;
+
+        let mean_square = self
+            .block
+            .iter()
+            .map(|&x| x as f64 * x as f64)
+            .sum::<f64>()
+            / n as f64;
+        let level_db = power_to_db(mean_square);
+        if level_db < DROPOUT_DB {
+            self.silent_blocks += 1;
+            if !self.in_dropout {
+                self.in_dropout = true;
+                self.report.dropouts += 1;
+                self.report.total_dropouts += 1;
+            }
+            return;
+        }
+        self.in_dropout = false;
+        self.level_sum += level_db;
+
+        if self.tone_power(&self.block) / mean_square < MIN_PURITY {
+            self.report.glitches += 1;
+            self.report.total_glitches += 1;
+            return;
+        }
+
+        if let Some(freq) = zero_crossing_frequency(&self.block, self.rate) {
+            let error = freq - self.freq;
+            self.freq_error_sum += error;
+            self.freq_blocks += 1;
+            if error.abs() > self.report.max_freq_error_hz.abs() {
+                self.report.max_freq_error_hz = error;
+            }
+        }
+    }
+
+    /// Power of the component at the tone frequency, from a Hann windowed
+    /// Goertzel filter.
+    fn tone_power(&self, block: &[f32]) -> f64 {
+        let n = block.len();
+        let omega = 2.0 * PI * self.freq / self.rate as f64;
+        let coeff = 2.0 * omega.cos();
+
+        let (mut s1, mut s2, mut window_sum) = (0.0, 0.0, 0.0);
+        for (i, &x) in block.iter().enumerate() {
+            let window = 0.5 - 0.5 * (2.0 * PI * i as f64 / (n - 1) as f64).cos();
+            let s = x as f64 * window + coeff * s1 - s2;
+            s2 = s1;
+            s1 = s;
+            window_sum += window;
+        }
+
+        let magnitude = (s1 * s1 + s2 * s2 - coeff * s1 * s2).max(0.0).sqrt();
+        let amplitude = 2.0 * magnitude / window_sum;
+        amplitude * amplitude / 2.0
+    }
+}
+
+/// Frequency from the distance between the first and the last rising zero
+/// crossing, each interpolated between the samples around it.
+fn zero_crossing_frequency(block: &[f32], rate: u32) -> Option<f64> {
+    let mut first = None;
+    let mut last = 0.0;
+    let mut crossings = 0;
+
+    for i in 1..block.len() {
+        let (a, b) = (block[i - 1] as f64, block[i] as f64);
+        if a < 0.0 && b >= 0.0 {
+            let t = (i - 1) as f64 + -a / (b - a);
+            if first.is_none() {
+                first = Some(t);
+            }
+            last = t;
+            crossings += 1;
+        }
+    }
+
+    match first {
+        Some(first) if crossings >= 2 && last > first => {
+            Some((crossings - 1) as f64 * rate as f64 / (last - first))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,695 @@
+use gst;
+use gst::prelude::*;
+use gst_app;
+
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+
+use failure::Error;
+
+use libc;
+
+use common::{make_bin, make_element};
+use config::{Config, ConfigValueError};
+use rtpdump::RtpdumpReader;
+
+/// Payload of the registration packets a receiver behind NAT sends to the
+/// transmitter. Anything else arriving on the transmitter socket is ignored.
+const KEEPALIVE: &[u8] = b"TLINK-KEEPALIVE";
+
+/// Largest datagram read from the sockets, well above any Opus RTP packet.
+const MAX_PACKET_SIZE: usize = 65536;
+
+/// Packets queued for a TCP connection before new ones are dropped.
+const TCP_SEND_QUEUE: usize = 256;
+
+#[derive(Debug, Fail)]
+#[fail(display = "Could not resolve {}", _0)]
+struct UnresolvedAddress(String);
+
+/// How packets travel between the ends, from the `transport` config key.
+///
+/// `udp` sends each RTP packet as a datagram. `tcp` is for venues that block
+/// UDP: the transmitter connects to the receiver and carries the same RTP
+/// stream framed as in RFC 4571, reconnecting whenever the connection drops.
+/// A connection that carries nothing, or blocks a write, for `tcp.timeout`
+/// ms counts as dropped, and a new connection from the transmitter
+/// replaces the current one.
+/// `srt` carries the RTP packets as SRT messages to get SRT's retransmission
+/// on long-haul links and to talk to SRT gateways. `replay` is for the
+/// receiver only: it plays the rtpdump capture `replay.file` into the
+/// pipeline with the original packet timing instead of listening.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Transport {
+    Udp,
+    Tcp,
+    Srt,
+    Replay,
+}
+
+impl Transport {
+    /// Reads `transport`, rejecting a `nat.mode` other than `off` with
+    /// anything but UDP: the NAT modes only replace the UDP socket, so the
+    /// setting would be silently ignored.
+    fn from_config(config: &Config) -> Result<Transport, Error> {
+        let transport = match config.get_str("transport") {
+            None | Some("udp") => Transport::Udp,
+            Some("tcp") => Transport::Tcp,
+            Some("srt") => Transport::Srt,
+            Some("replay") => Transport::Replay,
+            Some(other) => {
+                return Err(Error::from(ConfigValueError(
+                    "transport".into(),
+                    other.into(),
+                )))
+            }
+        };
+        if transport != Transport::Udp && NatMode::from_config(config)? != NatMode::Off {
+            return Err(Error::from(ConfigValueError(
+                "nat.mode".into(),
+                "only works with transport = udp".into(),
+            )));
+        }
+        Ok(transport)
+    }
+}
+
+/// NAT traversal role from the `nat.mode` config key.
+///
+/// Normally the transmitter sends to a receiver that is reachable at a known
+/// address. When only the transmitter is reachable, the receiver runs in
+/// `register` mode and sends keepalives to the transmitter (`nat.peer`), and
+/// the transmitter runs in `listen` mode and streams back to whichever
+/// address those keepalives come from. Both ends use a single socket, so the
+/// stream follows the mapping the keepalives opened in the receiver's NAT.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NatMode {
+    Off,
+    Register,
+    Listen,
+}
+
+impl NatMode {
+    fn from_config(config: &Config) -> Result<NatMode, Error> {
+        match config.get_str("nat.mode") {
+            None | Some("off") => Ok(NatMode::Off),
+            Some("register") => Ok(NatMode::Register),
+            Some("listen") => Ok(NatMode::Listen),
+            Some(other) => Err(Error::from(ConfigValueError(
+                "nat.mode".into(),
+                other.into(),
+            ))),
+        }
+    }
+}
+
+fn resolve(address: &str) -> Result<SocketAddr, Error> {
+    address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| Error::from(UnresolvedAddress(address.to_string())))
+}
+
+/// Sets an integer socket option, for the ones std does not expose. All
+/// the setsockopt calls go through here so the unsafe block stays in one
+/// place and a failed call always reports the OS error.
+fn set_socket_option<S: AsRawFd>(socket: &S, level: i32, name: i32, value: i32) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const i32 as *const libc::c_void,
+            mem::size_of::<i32>() as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// `port` as given on the command line or in the config, checked to be a
+/// valid port number before binding to it.
+fn port_number(port: i32) -> Result<u16, Error> {
+    if port < 0 || port > 65535 {
+        return Err(Error::from(ConfigValueError("port".into(), port.to_string())));
+    }
+    Ok(port as u16)
+}
+
+/// DSCP code point for the outgoing packets from `udp.dscp`, either a
+/// number or a per-hop behaviour name. Audio defaults to expedited
+/// forwarding; `none` leaves the marking to the system.
+fn dscp_from_config(config: &Config) -> Result<i32, Error> {
+    let value = config.get_str("udp.dscp").unwrap_or("ef").to_lowercase();
+    let dscp = match value.as_str() {
+        "none" => -1,
+        "ef" => 46,
+        "be" | "cs0" => 0,
+        "af11" => 10,
+        "af12" => 12,
+        "af13" => 14,
+        "af21" => 18,
+        "af22" => 20,
+        "af23" => 22,
+        "af31" => 26,
+        "af32" => 28,
+        "af33" => 30,
+        "af41" => 34,
+        "af42" => 36,
+        "af43" => 38,
+        _ if value.starts_with("cs") => match value[2..].parse::<i32>() {
+            Ok(class) if class >= 0 && class <= 7 => class << 3,
+            _ => return Err(Error::from(ConfigValueError("udp.dscp".into(), value))),
+        },
+        _ => match value.parse::<i32>() {
+            Ok(dscp) if dscp >= 0 && dscp <= 63 => dscp,
+            _ => return Err(Error::from(ConfigValueError("udp.dscp".into(), value))),
+        },
+    };
+    Ok(dscp)
+}
+
+/// Applies `udp.dscp` and `udp.buffer-size` to a socket of the NAT modes.
+/// The plain UDP mode sets them through the `qos-dscp` and `buffer-size`
+/// properties of udpsrc and udpsink, but the NAT modes send and receive on
+/// one std socket shared with the keepalive thread, so there is no udpsrc
+/// or udpsink to set them on. The socket both sends and receives, so the
+/// size applies to both buffers.
+fn configure_udp_socket(socket: &UdpSocket, config: &Config) -> Result<(), Error> {
+    let dscp = dscp_from_config(config)?;
+    if dscp >= 0 {
+        // The DSCP is the upper six bits of the traffic class byte.
+        match socket.local_addr()? {
+            SocketAddr::V4(_) => set_socket_option(socket, libc::IPPROTO_IP, libc::IP_TOS, dscp << 2)?,
+            SocketAddr::V6(_) => set_socket_option(socket, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, dscp << 2)?,
+        }
+    }
+    if let Some(size) = config.get::<i32>("udp.buffer-size")? {
+        set_socket_option(socket, libc::SOL_SOCKET, libc::SO_RCVBUF, size)?;
+        set_socket_option(socket, libc::SOL_SOCKET, libc::SO_SNDBUF, size)?;
+    }
+    Ok(())
+}
+
+/// Caps of the RFC 4571 framed form of the `application/x-rtp` or
+/// `application/x-srtp` stream described by `caps`.
+fn to_stream_caps(caps: &gst::Caps) -> gst::Caps {
+    let mut stream_caps = caps.copy();
+    {
+        let stream_caps = stream_caps.get_mut().unwrap();
+        let structure = stream_caps.get_mut_structure(0).unwrap();
+        let name = format!("{}-stream", structure.get_name());
+        structure.set_name(&name);
+    }
+    stream_caps
+}
+
+fn make_appsrc(caps: &gst::Caps) -> Result<(gst::Element, gst_app::AppSrc), Error> {
+    let element = make_element("appsrc", None)?;
+    element.set_property("is-live", &true.to_value())?;
+    element.set_property("do-timestamp", &true.to_value())?;
+    element.set_property_from_str("format", "time");
+
+    let appsrc = element
+        .clone()
+        .dynamic_cast::<gst_app::AppSrc>()
+        .expect("Source element is expected to be an appsrc!");
+    appsrc.set_caps(caps);
+
+    Ok((element, appsrc))
+}
+
+fn make_appsink(sync: bool) -> Result<(gst::Element, gst_app::AppSink), Error> {
+    let element = make_element("appsink", None)?;
+    element.set_property("sync", &sync.to_value())?;
+
+    let appsink = element
+        .clone()
+        .dynamic_cast::<gst_app::AppSink>()
+        .expect("Sink element is expected to be an appsink!");
+
+    Ok((element, appsink))
+}
+
+/// Calls `send` with the contents of every buffer reaching the appsink.
+fn forward_samples<F>(appsink: &gst_app::AppSink, send: F)
+where
+    F: Fn(&[u8]) + Send + Sync + 'static,
+{
+    appsink.set_callbacks(
+        gst_app::AppSinkCallbacks::new()
+            .new_sample(move |appsink| {
+                let sample = match appsink.pull_sample() {
+                    None => return gst::FlowReturn::Eos,
+                    Some(sample) => sample,
+                };
+
+                if let Some(buffer) = sample.get_buffer() {
+                    if let Some(map) = buffer.map_readable() {
+                        send(map.as_slice());
+                    }
+                }
+
+                gst::FlowReturn::Ok
+            })
+            .build(),
+    );
+}
+
+fn push_packet(appsrc: &gst_app::AppSrc, data: &[u8]) -> gst::FlowReturn {
+    match gst::Buffer::from_slice(data.to_vec()) {
+        Some(buffer) => appsrc.push_buffer(buffer),
+        None => gst::FlowReturn::Error,
+    }
+}
+
+/// Makes the element that delivers RTP from the network into the receiver's
+/// rtpbin. `caps` are set on its src pad.
+pub fn make_receiver_source(config: &Config, port: i32, caps: &gst::Caps) -> Result<gst::Element, Error> {
+    match Transport::from_config(config)? {
+        Transport::Tcp => return make_tcp_source(config, port, caps),
+        Transport::Srt => return make_srt_source(config, port, caps),
+        Transport::Replay => return make_replay_source(config, caps),
+        Transport::Udp => (),
+    }
+
+    match NatMode::from_config(config)? {
+        NatMode::Register => make_register_source(config, port, caps),
+        _ => {
+            let udpsrc = make_element("udpsrc", None)?;
+            udpsrc.set_property("port", &port.to_value())?;
+            udpsrc.set_property("caps", &caps.to_value())?;
+            if let Some(size) = config.get::<i32>("udp.buffer-size")? {
+                udpsrc.set_property("buffer-size", &size.to_value())?;
+            }
+            Ok(udpsrc)
+        }
+    }
+}
+
+fn make_register_source(config: &Config, port: i32, caps: &gst::Caps) -> Result<gst::Element, Error> {
+    let peer = match config.get_str("nat.peer") {
+        Some(peer) => resolve(peer)?,
+        None => return Err(Error::from(ConfigValueError("nat.peer".into(), "missing".into()))),
+    };
+    let interval = time::Duration::from_millis(config.get_or("nat.keepalive", 1000u64)?);
+
+    let socket = UdpSocket::bind(("0.0.0.0", port_number(port)?))?;
+    configure_udp_socket(&socket, config)?;
+    let keepalive_socket = socket.try_clone()?;
+    let (element, appsrc) = make_appsrc(caps)?;
+
+    thread::spawn(move || loop {
+        if let Err(err) = keepalive_socket.send_to(KEEPALIVE, peer) {
+            eprintln!("Failed to send keepalive to {}: {}", peer, err);
+        }
+        thread::sleep(interval);
+    });
+
+    thread::spawn(move || {
+        let mut data = vec![0u8; MAX_PACKET_SIZE];
+        loop {
+            let len = match socket.recv_from(&mut data) {
+                Ok((len, from)) if from == peer => len,
+                // Only the transmitter gets through the mapping, but anyone
+                // can send to the port while it is open.
+                Ok(_) => continue,
+                Err(err) => {
+                    eprintln!("Failed to receive from {}: {}", peer, err);
+                    continue;
+                }
+            };
+
+            // Packets arriving before the pipeline plays, or while it is
+            // flushing, are dropped.
+            let _ = push_packet(&appsrc, &data[..len]);
+        }
+    });
+
+    Ok(element)
+}
+
+fn make_replay_source(config: &Config, caps: &gst::Caps) -> Result<gst::Element, Error> {
+    let path = match config.get_str("replay.file") {
+        Some(path) => path.to_string(),
+        None => return Err(Error::from(ConfigValueError("replay.file".into(), "missing".into()))),
+    };
+    let mut reader = RtpdumpReader::open(&path)?;
+    let (element, appsrc) = make_appsrc(caps)?;
+
+    // Packets pushed before the pipeline is playing would be refused, so
+    // the replay starts when the appsrc first asks for data.
+    let (started, wait_started) = mpsc::sync_channel(1);
+    let started = Mutex::new(started);
+    appsrc.set_callbacks(
+        gst_app::AppSrcCallbacks::new()
+            .need_data(move |_, _| {
+                let _ = started.lock().unwrap().try_send(());
+            })
+            .build(),
+    );
+
+    thread::spawn(move || {
+        if wait_started.recv().is_err() {
+            return;
+        }
+        let start = time::Instant::now();
+        loop {
+            let (offset, packet) = match reader.next_packet() {
+                Ok(Some(next)) => next,
+                Ok(None) => {
+                    eprintln!("Replay of {} finished", path);
+                    let _ = appsrc.end_of_stream();
+                    break;
+                }
+                Err(err) => {
+                    eprintln!("Failed to read {}: {}", path, err);
+                    let _ = appsrc.end_of_stream();
+                    break;
+                }
+            };
+
+            let elapsed = start.elapsed();
+            if offset > elapsed {
+                thread::sleep(offset - elapsed);
+            }
+            if push_packet(&appsrc, &packet) != gst::FlowReturn::Ok {
+                break;
+            }
+        }
+    });
+
+    Ok(element)
+}
+
+/// Makes the element that sends the transmitter's rtpbin output to the
+/// receiver at `address`:`port`, or in NAT listen mode waits for the
+/// receiver to register on that local address.
+pub fn make_transmitter_sink(config: &Config, address: &str, port: i32, sync: bool) -> Result<gst::Element, Error> {
+    match Transport::from_config(config)? {
+        Transport::Tcp => return make_tcp_sink(config, address, port, sync),
+        Transport::Srt => return make_srt_sink(config, address, port, sync),
+        Transport::Replay => {
+            return Err(Error::from(ConfigValueError(
+                "transport".into(),
+                "replay only works on the receiver".into(),
+            )))
+        }
+        Transport::Udp => (),
+    }
+
+    match NatMode::from_config(config)? {
+        NatMode::Listen => make_listen_sink(config, address, port, sync),
+        _ => {
+            let udpsink = make_element("udpsink", None)?;
+            udpsink.set_property("host", &address.to_value())?;
+            udpsink.set_property("sync", &sync.to_value())?;
+            udpsink.set_property("port", &port.to_value())?;
+            udpsink.set_property("qos-dscp", &dscp_from_config(config)?.to_value())?;
+            if let Some(size) = config.get::<i32>("udp.buffer-size")? {
+                udpsink.set_property("buffer-size", &size.to_value())?;
+            }
+            Ok(udpsink)
+        }
+    }
+}
+
+fn make_listen_sink(config: &Config, address: &str, port: i32, sync: bool) -> Result<gst::Element, Error> {
+    let timeout = time::Duration::from_millis(config.get_or("nat.timeout", 10000u64)?);
+
+    let socket = UdpSocket::bind((address, port_number(port)?))?;
+    configure_udp_socket(&socket, config)?;
+    let send_socket = socket.try_clone()?;
+    let peer: Arc<Mutex<Option<(SocketAddr, time::Instant)>>> = Arc::new(Mutex::new(None));
+    let (element, appsink) = make_appsink(sync)?;
+
+    let registered = peer.clone();
+    thread::spawn(move || {
+        let mut data = vec![0u8; MAX_PACKET_SIZE];
+        loop {
+            let (len, from) = match socket.recv_from(&mut data) {
+                Ok(received) => received,
+                Err(err) => {
+                    eprintln!("Failed to receive keepalive: {}", err);
+                    continue;
+                }
+            };
+            if &data[..len] != KEEPALIVE {
+                continue;
+            }
+
+            let mut registered = registered.lock().unwrap();
+            if registered.map(|(addr, _)| addr) != Some(from) {
+                println!("Receiver registered from {}", from);
+            }
+            *registered = Some((from, time::Instant::now()));
+        }
+    });
+
+    forward_samples(&appsink, move |data| {
+        let target = match *peer.lock().unwrap() {
+            Some((addr, seen)) if seen.elapsed() < timeout => addr,
+            _ => return,
+        };
+        if let Err(err) = send_socket.send_to(data, target) {
+            eprintln!("Failed to send to {}: {}", target, err);
+        }
+    });
+
+    Ok(element)
+}
+
+/// Reads one RFC 4571 frame, returning it with its length prefix so that
+/// rtpstreamdepay only ever sees whole frames, even across reconnects.
+fn read_frame(stream: &mut TcpStream) -> Result<Vec<u8>, Error> {
+    let mut frame = vec![0u8; 2];
+    stream.read_exact(&mut frame)?;
+    let len = ((frame[0] as usize) << 8) | frame[1] as usize;
+    frame.resize(2 + len, 0);
+    stream.read_exact(&mut frame[2..])?;
+    Ok(frame)
+}
+
+/// Time without data, or with a write blocked, after which a TCP
+/// connection counts as lost, from `tcp.timeout` in ms. The transmitter
+/// defaults to its `tcp.reconnect` interval, the receiver to one second,
+/// both far longer than the gap between two packets.
+fn tcp_timeout(config: &Config, default_ms: u64) -> Result<time::Duration, Error> {
+    let timeout_ms = config.get_or("tcp.timeout", default_ms)?;
+    if timeout_ms == 0 {
+        return Err(Error::from(ConfigValueError("tcp.timeout".into(), "0".into())));
+    }
+    Ok(time::Duration::from_millis(timeout_ms))
+}
+
+/// Sets up a new TCP connection for low latency streaming: no Nagle, and
+/// keepalives so an idle connection through a NAT is not dropped silently.
+fn setup_tcp_stream(stream: &TcpStream, timeout: time::Duration) -> Result<(), Error> {
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    set_socket_option(stream, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+    Ok(())
+}
+
+/// Pushes the frames arriving on `stream` until it fails, times out or is
+/// shut down by a newer connection.
+fn receive_frames(mut stream: TcpStream, appsrc: &gst_app::AppSrc) {
+    let peer = stream.peer_addr().ok();
+    println!("Transmitter connected from {:?}", peer);
+
+    loop {
+        let frame = match read_frame(&mut stream) {
+            Ok(frame) => frame,
+            Err(err) => {
+                eprintln!("Transmitter {:?} disconnected: {}", peer, err);
+                return;
+            }
+        };
+        // Frames arriving before the pipeline plays, or while it is
+        // flushing, are dropped.
+        let _ = push_packet(appsrc, &frame);
+    }
+}
+
+fn make_tcp_source(config: &Config, port: i32, caps: &gst::Caps) -> Result<gst::Element, Error> {
+    let timeout = tcp_timeout(config, 1000)?;
+    let listener = TcpListener::bind(("0.0.0.0", port_number(port)?))?;
+    let (appsrc_element, appsrc) = make_appsrc(&to_stream_caps(caps))?;
+    let depay = make_element("rtpstreamdepay", None)?;
+
+    thread::spawn(move || {
+        let mut current: Option<TcpStream> = None;
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    eprintln!("Failed to accept TCP connection: {}", err);
+                    continue;
+                }
+            };
+            if let Err(err) = setup_tcp_stream(&stream, timeout) {
+                eprintln!("Failed to set up TCP connection: {}", err);
+                continue;
+            }
+
+            // A transmitter that reconnects, having noticed a dead
+            // connection before the receiver did, replaces the old one.
+            if let Some(old) = current.take() {
+                let _ = old.shutdown(Shutdown::Both);
+            }
+            current = stream.try_clone().ok();
+
+            let appsrc = appsrc.clone();
+            thread::spawn(move || receive_frames(stream, &appsrc));
+        }
+    });
+
+    make_bin(&[&appsrc_element, &depay])
+}
+
+fn make_tcp_sink(config: &Config, address: &str, port: i32, sync: bool) -> Result<gst::Element, Error> {
+    let target = resolve(&format!("{}:{}", address, port))?;
+    let reconnect_ms = config.get_or("tcp.reconnect", 1000u64)?;
+    let reconnect = time::Duration::from_millis(reconnect_ms);
+    let timeout = tcp_timeout(config, reconnect_ms)?;
+
+    let pay = make_element("rtpstreampay", None)?;
+    let (appsink_element, appsink) = make_appsink(sync)?;
+    let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(TCP_SEND_QUEUE);
+    let sender = Mutex::new(sender);
+
+    // Packets that pile up while there is no connection are dropped; the
+    // receiver's jitterbuffer could not use them anymore anyway.
+    forward_samples(&appsink, move |data| {
+        let _ = sender.lock().unwrap().try_send(data.to_vec());
+    });
+
+    thread::spawn(move || loop {
+        match TcpStream::connect(target) {
+            Ok(mut stream) => {
+                if let Err(err) = setup_tcp_stream(&stream, timeout) {
+                    eprintln!("Failed to set up connection to {}: {}", target, err);
+                    thread::sleep(reconnect);
+                    continue;
+                }
+                println!("Connected to receiver at {}", target);
+                while receiver.try_recv().is_ok() {}
+
+                // A write blocked for the whole timeout, e.g. by a receiver
+                // that vanished without closing, counts as a lost connection
+                // just like an error does.
+                for frame in receiver.iter() {
+                    if let Err(err) = stream.write_all(&frame) {
+                        eprintln!("Connection to {} lost: {}", target, err);
+                        let _ = stream.shutdown(Shutdown::Both);
+                        break;
+                    }
+                }
+            }
+            Err(err) => eprintln!("Failed to connect to {}: {}", target, err),
+        }
+        thread::sleep(reconnect);
+    });
+
+    make_bin(&[&pay, &appsink_element])
+}
+
+/// Builds the SRT URI for connecting to or listening on `host`:`port`.
+/// `srt.mode` is caller, listener or rendezvous; `default_mode` applies when
+/// it is not set.
+fn srt_uri(config: &Config, host: &str, port: i32, default_mode: &str) -> Result<String, Error> {
+    let mode = config.get_str("srt.mode").unwrap_or(default_mode);
+    match mode {
+        "caller" | "listener" | "rendezvous" => (),
+        _ => return Err(Error::from(ConfigValueError("srt.mode".into(), mode.into()))),
+    }
+
+    let mut uri = format!(
+        "srt://{}:{}?mode={}&latency={}",
+        host,
+        port,
+        mode,
+        config.get_or("srt.latency", 125u32)?
+    );
+    if let Some(pbkeylen) = config.get::<u32>("srt.pbkeylen")? {
+        uri.push_str(&format!("&pbkeylen={}", pbkeylen));
+    }
+
+    Ok(uri)
+}
+
+fn set_srt_properties(element: &gst::Element, config: &Config, uri: &str) -> Result<(), Error> {
+    element.set_property("uri", &uri.to_value())?;
+    if let Some(passphrase) = config.get_str("srt.passphrase") {
+        element.set_property("passphrase", &passphrase.to_value())?;
+    }
+    Ok(())
+}
+
+/// The receiver listens on `port` by default. As a caller or in rendezvous
+/// mode it connects to `srt.peer` instead.
+fn make_srt_source(config: &Config, port: i32, caps: &gst::Caps) -> Result<gst::Element, Error> {
+    let uri = match config.get_str("srt.peer") {
+        Some(peer) => {
+            let peer = resolve(peer)?;
+            srt_uri(config, &peer.ip().to_string(), peer.port() as i32, "caller")?
+        }
+        None => srt_uri(config, "", port, "listener")?,
+    };
+
+    let srtsrc = make_element("srtsrc", None)?;
+    set_srt_properties(&srtsrc, config, &uri)?;
+    srtsrc.set_property("caps", &caps.to_value())?;
+
+    Ok(srtsrc)
+}
+
+/// The transmitter calls the receiver at `address`:`port` by default; as a
+/// listener `address` is the local address to listen on.
+fn make_srt_sink(config: &Config, address: &str, port: i32, sync: bool) -> Result<gst::Element, Error> {
+    let uri = srt_uri(config, address, port, "caller")?;
+
+    let srtsink = make_element("srtsink", None)?;
+    set_srt_properties(&srtsink, config, &uri)?;
+    srtsink.set_property("sync", &sync.to_value())?;
+
+    Ok(srtsink)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_settings(settings: &[(&str, &str)]) -> Config {
+        let mut config = Config::default();
+        for &(key, value) in settings {
+            config.set(key, value);
+        }
+        config
+    }
+
+    #[test]
+    fn nat_mode_works_with_udp() {
+        let config = with_settings(&[("transport", "udp"), ("nat.mode", "register")]);
+        assert_eq!(Transport::from_config(&config).unwrap(), Transport::Udp);
+        assert_eq!(NatMode::from_config(&config).unwrap(), NatMode::Register);
+    }
+
+    #[test]
+    fn nat_mode_is_rejected_with_tcp() {
+        let config = with_settings(&[("transport", "tcp"), ("nat.mode", "listen")]);
+        assert!(Transport::from_config(&config).is_err());
+
+        let config = with_settings(&[("transport", "tcp"), ("nat.mode", "off")]);
+        assert_eq!(Transport::from_config(&config).unwrap(), Transport::Tcp);
+    }
+}
@@ -0,0 +1,292 @@
+//! Runs a transmitter and a receiver pipeline in one process over
+//! localhost, with loss injected in front of the receiver's rtpbin. The RTP
+//! side of both is built by the same `link` functions as the binaries'.
+
+extern crate gstreamer as gst;
+use gst::prelude::*;
+extern crate gstreamer_app as gst_app;
+
+extern crate failure;
+use failure::Error;
+
+extern crate wappuradio_tlink;
+use wappuradio_tlink::common;
+use common::make_element;
+
+use wappuradio_tlink::config;
+use config::Config;
+
+use wappuradio_tlink::impairment;
+use impairment::LossCounts;
+
+use wappuradio_tlink::link;
+use link::{FecPercentages, ReceiverLink};
+
+use wappuradio_tlink::rtp;
+
+use std::env;
+use std::fs;
+use std::net::UdpSocket;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+
+const RUN_TIME: time::Duration = time::Duration::from_secs(4);
+/// Jitterbuffer latency and FEC storage of the receiver, in ms.
+const LATENCY: u32 = 200;
+const STORAGE: u64 = 1000;
+
+const ELEMENTS: &[&str] = &[
+    "audiotestsrc",
+    "audioconvert",
+    "opusenc",
+    "opusdec",
+    "rtpopuspay",
+    "rtpopusdepay",
+    "rtpbin",
+    "rtpulpfecenc",
+    "rtpulpfecdec",
+    "udpsrc",
+    "udpsink",
+    "appsink",
+];
+
+/// Fails the test when GStreamer or the plugins the link needs are not
+/// installed, rather than letting it pass without running.
+fn init() {
+    gst::init().expect("GStreamer unavailable");
+
+    let missing = ELEMENTS
+        .iter()
+        .filter(|name| gst::ElementFactory::find(name).is_none())
+        .collect::<Vec<_>>();
+    assert!(missing.is_empty(), "missing elements {:?}", missing);
+}
+
+fn free_port() -> i32 {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    socket.local_addr().unwrap().port() as i32
+}
+
+/// A config with the `loss.*` keys of a loss model.
+fn loss_config(settings: &[(&str, &str)]) -> Config {
+    let mut config = Config::default();
+    for &(key, value) in settings {
+        config.set(key, value);
+    }
+    config
+}
+
+/// A config dropping packets by the `pattern` of a loss trace.
+fn trace_loss(name: &str, pattern: &str) -> Config {
+    let path = env::temp_dir()
+        .join(format!("tlink-{}-{}.trace", process::id(), name))
+        .to_string_lossy()
+        .into_owned();
+    fs::write(&path, pattern).unwrap();
+    loss_config(&[("loss.model", "trace"), ("loss.trace", path.as_str())])
+}
+
+/// The transmitter fed by a test tone instead of JACK.
+fn make_transmitter(port: i32, percentage: u32) -> Result<gst::Pipeline, Error> {
+    let pipeline = gst::Pipeline::new(None);
+    let audiotestsrc = make_element("audiotestsrc", None)?;
+    let audioconvert = make_element("audioconvert", None)?;
+    let opusenc = make_element("opusenc", None)?;
+    let rtpopuspay = make_element("rtpopuspay", None)?;
+
+    pipeline.add_many(&[&audiotestsrc, &audioconvert, &opusenc, &rtpopuspay])?;
+    gst::Element::link_many(&[&audiotestsrc, &audioconvert, &opusenc, &rtpopuspay])?;
+
+    let fec = FecPercentages {
+        percentage,
+        important: 0,
+    };
+    link::make_transmitter(&pipeline, &Config::default(), &rtpopuspay, "127.0.0.1", port, false, fec)?;
+
+    audiotestsrc.set_property("is-live", &true.to_value())?;
+    rtpopuspay.set_property("pt", &rtp::OPUS_PT.to_value())?;
+
+    Ok(pipeline)
+}
+
+struct Receiver {
+    pipeline: gst::Pipeline,
+    audio_ns: Arc<AtomicUsize>,
+    jitterbuffer: Arc<Mutex<Option<gst::Element>>>,
+    injected: Option<Arc<LossCounts>>,
+}
+
+impl Receiver {
+    /// The receiver with an appsink in place of JACK, dropping packets as
+    /// the `loss.*` keys of `config` say.
+    fn new(port: i32, config: &Config) -> Result<Receiver, Error> {
+        let pipeline = gst::Pipeline::new(None);
+        let depay = make_element("rtpopusdepay", None)?;
+        let opusdec = make_element("opusdec", None)?;
+        let audioconvert = make_element("audioconvert", None)?;
+        let appsink = make_element("appsink", None)?;
+
+        pipeline.add_many(&[&depay, &opusdec, &audioconvert, &appsink])?;
+        gst::Element::link_many(&[&depay, &opusdec, &audioconvert, &appsink])?;
+
+        let ReceiverLink {
+            rtpbin,
+            injected_loss: injected,
+            ..
+        } = link::make_receiver(&pipeline, config, port, None, LATENCY, STORAGE, vec![depay])?;
+
+        let jitterbuffer = Arc::new(Mutex::new(None));
+        let new_jitterbuffer = jitterbuffer.clone();
+        rtpbin.connect("new-jitterbuffer", false, move |values| {
+            *new_jitterbuffer.lock().unwrap() = values[1].get::<gst::Element>();
+            None
+        })?;
+
+        let audio_ns = Arc::new(AtomicUsize::new(0));
+        let sample_audio_ns = audio_ns.clone();
+        let appsink = appsink
+            .dynamic_cast::<gst_app::AppSink>()
+            .expect("Sink element is expected to be an appsink!");
+        appsink.set_property("sync", &false.to_value())?;
+        appsink.set_callbacks(
+            gst_app::AppSinkCallbacks::new()
+                .new_sample(move |appsink| {
+                    let sample = match appsink.pull_sample() {
+                        None => return gst::FlowReturn::Eos,
+                        Some(sample) => sample,
+                    };
+                    if let Some(duration) = sample.get_buffer().and_then(|b| b.get_duration().nseconds()) {
+                        sample_audio_ns.fetch_add(duration as usize, Ordering::Relaxed);
+                    }
+                    gst::FlowReturn::Ok
+                })
+                .build(),
+        );
+
+        opusdec.set_property("plc", &true.to_value())?;
+
+        Ok(Receiver {
+            pipeline,
+            audio_ns,
+            jitterbuffer,
+            injected,
+        })
+    }
+
+    fn audio(&self) -> time::Duration {
+        time::Duration::from_nanos(self.audio_ns.load(Ordering::Relaxed) as u64)
+    }
+
+    fn fec_recovered(&self) -> u32 {
+        self.pipeline
+            .get_by_name("fecdec")
+            .and_then(|fecdec| fecdec.get_property("recovered").ok())
+            .and_then(|value| value.get::<u32>())
+            .unwrap_or(0)
+    }
+
+    fn jitterbuffer_lost(&self) -> u64 {
+        self.jitterbuffer
+            .lock()
+            .unwrap()
+            .as_ref()
+            .and_then(|jitterbuffer| jitterbuffer.get_property("stats").ok())
+            .and_then(|value| value.get::<gst::Structure>())
+            .and_then(|stats| stats.get::<u64>("num-lost"))
+            .unwrap_or(0)
+    }
+
+    fn injected_lost(&self) -> usize {
+        self.injected.as_ref().map(|counts| counts.lost()).unwrap_or(0)
+    }
+}
+
+fn assert_no_errors(pipeline: &gst::Pipeline) {
+    let bus = pipeline.get_bus().expect("Pipeline without bus. Shouldn't happen!");
+    while let Some(msg) = bus.pop() {
+        if let gst::MessageView::Error(err) = msg.view() {
+            panic!(
+                "Error from {:?}: {} ({:?})",
+                msg.get_src().map(|s| s.get_path_string()),
+                err.get_error(),
+                err.get_debug()
+            );
+        }
+    }
+}
+
+/// Streams for `RUN_TIME` and returns the receiver for inspection.
+fn run_link(percentage: u32, config: &Config) -> Receiver {
+    let port = free_port();
+    let receiver = Receiver::new(port, config).unwrap();
+    let transmitter = make_transmitter(port, percentage).unwrap();
+
+    assert_ne!(receiver.pipeline.set_state(gst::State::Playing), gst::StateChangeReturn::Failure);
+    assert_ne!(transmitter.set_state(gst::State::Playing), gst::StateChangeReturn::Failure);
+    thread::sleep(RUN_TIME);
+
+    assert_no_errors(&transmitter);
+    assert_no_errors(&receiver.pipeline);
+    transmitter.set_state(gst::State::Null);
+    receiver.pipeline.set_state(gst::State::Null);
+
+    receiver
+}
+
+#[test]
+fn audio_arrives_over_clean_link() {
+    init();
+
+    let receiver = run_link(20, &Config::default());
+
+    assert!(receiver.audio() > RUN_TIME / 2, "only {:?} of audio", receiver.audio());
+    assert_eq!(receiver.jitterbuffer_lost(), 0);
+}
+
+#[test]
+fn fec_recovers_isolated_losses() {
+    init();
+
+    // With 100 % FEC every media packet has its own FEC packet, so dropping
+    // every seventh packet never takes out both.
+    let receiver = run_link(100, &trace_loss("isolated", "0000001"));
+
+    assert!(receiver.audio() > RUN_TIME / 2, "only {:?} of audio", receiver.audio());
+    assert!(receiver.injected_lost() > 0);
+    assert!(receiver.fec_recovered() > 0, "FEC recovered nothing");
+    assert!(
+        receiver.jitterbuffer_lost() < receiver.injected_lost() as u64,
+        "{} of {} dropped packets lost",
+        receiver.jitterbuffer_lost(),
+        receiver.injected_lost()
+    );
+}
+
+#[test]
+fn fec_reduces_burst_loss() {
+    init();
+
+    // The same loss pattern, once without FEC to compare against.
+    let config = loss_config(&[
+        ("loss.model", "gilbert-elliott"),
+        ("loss.p", "0.05"),
+        ("loss.r", "0.5"),
+        ("loss.good", "0"),
+        ("loss.bad", "1"),
+        ("loss.seed", "1234"),
+    ]);
+    let without_fec = run_link(0, &config);
+    let with_fec = run_link(20, &config);
+
+    assert!(with_fec.audio() > RUN_TIME / 2, "only {:?} of audio", with_fec.audio());
+    assert!(without_fec.jitterbuffer_lost() > 0, "the burst model dropped nothing");
+    assert!(
+        with_fec.jitterbuffer_lost() < without_fec.jitterbuffer_lost(),
+        "{} packets lost with FEC, {} without",
+        with_fec.jitterbuffer_lost(),
+        without_fec.jitterbuffer_lost()
+    );
+}